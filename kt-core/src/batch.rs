@@ -2,15 +2,20 @@ use std::cmp::min;
 use std::collections::VecDeque;
 
 use aho_corasick::{AhoCorasick, AhoCorasickBuilder, MatchKind};
+use itertools::Itertools;
 use ndarray::Array2;
 use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
 
+use crate::schedule::Schedule;
+
 pub struct Batcher {
     // settings
     batch_size: usize,
     seq_len: usize,
     bucket_count: usize,
+    keep_short_tails: bool,
+    shape: BatchShape,
     aho: AhoCorasick,
 
     // state
@@ -18,6 +23,26 @@ pub struct Batcher {
     stats: Stats,
     buckets: VecDeque<Bucket>,
     empty_buffers: VecDeque<VecDeque<usize>>,
+    // for dynamic batches, the rows waiting until there are enough of a similar length, indexed by length class
+    pending: Vec<Vec<Row>>,
+    schedule: Option<Schedule>,
+    // the index of the first batch, for resuming a schedule
+    start_batch: usize,
+}
+
+/// How the rows are grouped into batches.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BatchShape {
+    /// Every batch is `batch_size x seq_len`, rows from short samples are padded.
+    Fixed,
+    /// Rows are grouped by their length rounded up to a multiple of `pad_multiple`,
+    /// and each batch is only as wide as the rounded length of its group.
+    /// If `max_tokens` is set the number of rows is the largest one with `rows * width <= max_tokens`
+    /// instead of `batch_size`.
+    Dynamic {
+        pad_multiple: usize,
+        max_tokens: Option<usize>,
+    },
 }
 
 #[derive(Debug, Default, Copy, Clone)]
@@ -25,14 +50,76 @@ pub struct Stats {
     pub sample_count: usize,
     pub token_count: usize,
     pub batch_count: usize,
+
+    /// Tokens that ended up in a batch.
+    pub used_count: usize,
+    /// Padding tokens in rows shorter than `seq_len`.
+    pub padding_count: usize,
+    /// Tokens dropped by the random offset at the start of a sample.
+    pub dropped_start_count: usize,
+    /// Tokens dropped because fewer than `seq_len` were left at the end of a sample.
+    pub dropped_tail_count: usize,
 }
 
-pub struct Batch {
-    pub tokens: Array2<i32>,
+pub struct Batch<T = i32> {
+    /// The tokens, padded with [TokenId::PAD].
+    pub tokens: Array2<T>,
+    /// The number of real tokens in each row, the rest is padding.
+    pub lengths: Vec<usize>,
     pub samples: Vec<usize>,
     pub start_indices: Vec<usize>,
 }
 
+/// The element type of batches.
+pub trait TokenId: Copy + Send + 'static {
+    /// The value used for padding, -1 for signed and the maximum value for unsigned types.
+    const PAD: Self;
+    /// The number of token indices that can be represented without colliding with `PAD`.
+    const MAX_TOKENS: usize;
+
+    fn from_index(index: usize) -> Self;
+}
+
+impl TokenId for i32 {
+    const PAD: Self = -1;
+    const MAX_TOKENS: usize = i32::MAX as usize + 1;
+
+    fn from_index(index: usize) -> Self {
+        index as i32
+    }
+}
+
+impl TokenId for u16 {
+    const PAD: Self = u16::MAX;
+    const MAX_TOKENS: usize = u16::MAX as usize;
+
+    fn from_index(index: usize) -> Self {
+        index as u16
+    }
+}
+
+impl TokenId for u32 {
+    const PAD: Self = u32::MAX;
+    const MAX_TOKENS: usize = u32::MAX as usize;
+
+    fn from_index(index: usize) -> Self {
+        index as u32
+    }
+}
+
+impl<T> Batch<T> {
+    /// The token buffer, to be passed to [Batcher::pop_batch_into] again.
+    pub fn into_buffer(self) -> Vec<T> {
+        self.tokens.into_raw_vec()
+    }
+}
+
+struct Row {
+    sample: usize,
+    start_index: usize,
+    tokens: Vec<usize>,
+}
+
 pub struct Bucket {
     // which sample index this data originated from
     sample: usize,
@@ -43,12 +130,15 @@ pub struct Bucket {
     tokens: VecDeque<usize>,
 }
 
+/// Build a greedy longest-match tokenizer.
+///
+/// Bytes that don't match any token are silently skipped,
+/// [VocabEvaluator](crate::evaluate::VocabEvaluator) reports how many.
 pub fn build_tokenizer<I, P>(tokens: I) -> AhoCorasick
 where
     I: IntoIterator<Item = P>,
     P: AsRef<[u8]>,
 {
-    // TODO this silently skips bytes that don't match any tokens, is that okay?
     AhoCorasickBuilder::new()
         .match_kind(MatchKind::LeftmostLongest)
         .dfa(true)
@@ -72,14 +162,48 @@ impl Batcher {
             batch_size,
             seq_len,
             bucket_count,
+            keep_short_tails: false,
+            shape: BatchShape::Fixed,
             aho: build_tokenizer(tokens),
             rng: SmallRng::from_entropy(),
             stats: Stats::default(),
             buckets: VecDeque::default(),
             empty_buffers: VecDeque::default(),
+            pending: vec![],
+            schedule: None,
+            start_batch: 0,
         }
     }
 
+    /// Change the sequence length and batch size according to `schedule`.
+    /// `start_batch` is the index of the first batch this batcher produces, to continue a schedule when resuming.
+    pub fn with_schedule(mut self, schedule: Schedule, start_batch: usize) -> Self {
+        self.schedule = Some(schedule);
+        self.start_batch = start_batch;
+        self.apply_schedule();
+        self
+    }
+
+    pub fn with_shape(mut self, shape: BatchShape) -> Self {
+        if let BatchShape::Dynamic {
+            pad_multiple,
+            max_tokens,
+        } = shape
+        {
+            assert!(pad_multiple > 0, "Pad multiple cannot be zero");
+            assert!(max_tokens != Some(0), "Max tokens cannot be zero");
+        }
+        self.shape = shape;
+        self
+    }
+
+    /// Keep the tokens left at the end of a sample as a padded row instead of dropping them.
+    /// This trades dropped tokens for padding.
+    pub fn with_keep_short_tails(mut self, keep_short_tails: bool) -> Self {
+        self.keep_short_tails = keep_short_tails;
+        self
+    }
+
     pub fn push_sample(&mut self, sample: &str) -> bool {
         // don't even bother with empty sequences, they would create empty buckets
         if sample.is_empty() {
@@ -87,10 +211,7 @@ impl Batcher {
         }
 
         // pick a buffer to put the sequences into
-        let mut buffer = self
-            .empty_buffers
-            .pop_front()
-            .unwrap_or_else(VecDeque::default);
+        let mut buffer = self.empty_buffers.pop_front().unwrap_or_default();
         assert!(buffer.is_empty(), "Empty buffer is non-empty");
 
         // tokenize straight into buffer
@@ -117,51 +238,112 @@ impl Batcher {
         // update stats
         self.stats.sample_count += 1;
         self.stats.token_count += parsed_token_count;
+        self.stats.dropped_start_count += offset;
 
         true
     }
 
+    pub fn seq_len(&self) -> usize {
+        self.seq_len
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// The index of the next batch, including the start offset of the schedule.
+    pub fn batch_index(&self) -> usize {
+        self.start_batch + self.stats.batch_count
+    }
+
+    /// Change the sequence length and batch size, starting from the next batch.
+    ///
+    /// The buckets only contain the remaining tokens of each sample and are split into rows when a batch is built,
+    /// so they don't need to change. Rows that are waiting for a dynamic batch are split up if they are too long.
+    /// The bucket count is increased if it is less than the new batch size.
+    pub fn resize(&mut self, seq_len: usize, batch_size: usize) {
+        assert!(batch_size > 0, "Batch size cannot be zero");
+        assert!(seq_len > 0, "Sequence length cannot be zero");
+
+        let shrinking = seq_len < self.seq_len;
+        self.seq_len = seq_len;
+        self.batch_size = batch_size;
+        self.bucket_count = self.bucket_count.max(batch_size);
+
+        if let (true, BatchShape::Dynamic { pad_multiple, .. }) = (shrinking, self.shape) {
+            for row in std::mem::take(&mut self.pending).into_iter().flatten() {
+                for (i, chunk) in row.tokens.chunks(seq_len).enumerate() {
+                    // the same rule as for tails of buckets
+                    if i > 0 && chunk.len() < seq_len && !self.keep_short_tails {
+                        self.stats.dropped_tail_count += chunk.len();
+                        continue;
+                    }
+
+                    let row = Row {
+                        sample: row.sample,
+                        start_index: row.start_index + i * seq_len,
+                        tokens: chunk.to_vec(),
+                    };
+                    self.push_pending(row, pad_multiple);
+                }
+            }
+        }
+    }
+
+    fn apply_schedule(&mut self) {
+        let step = match &self.schedule {
+            Some(schedule) => schedule.step_at(self.batch_index()).copied(),
+            None => None,
+        };
+        if let Some(step) = step {
+            if (step.seq_len, step.batch_size) != (self.seq_len, self.batch_size) {
+                self.resize(step.seq_len, step.batch_size);
+            }
+        }
+    }
+
     pub fn pop_batch(&mut self) -> Option<Batch> {
+        self.pop_batch_into(&mut vec![])
+    }
+
+    /// Like [Batcher::pop_batch], but the tokens are stored in the allocation of `buffer` if a batch is returned.
+    /// The buffer of a batch that is no longer needed can be recovered with [Batch::into_buffer].
+    pub fn pop_batch_into<T: TokenId>(&mut self, buffer: &mut Vec<T>) -> Option<Batch<T>> {
+        self.apply_schedule();
+
+        match self.shape {
+            BatchShape::Fixed => self.pop_fixed_batch(buffer),
+            BatchShape::Dynamic {
+                pad_multiple,
+                max_tokens,
+            } => self.pop_dynamic_batch(buffer, pad_multiple, max_tokens),
+        }
+    }
+
+    fn pop_fixed_batch<T: TokenId>(&mut self, buffer: &mut Vec<T>) -> Option<Batch<T>> {
         if self.buckets.len() < self.bucket_count {
             return None;
         }
 
-        let mut batch: Array2<i32> = Array2::zeros((self.batch_size, self.seq_len));
-        batch.fill(-1);
+        let mut batch = padded_array(buffer, (self.batch_size, self.seq_len));
+        let mut lengths = vec![];
         let mut samples = vec![];
         let mut start_indices = vec![];
 
         for bi in 0..self.batch_size {
-            // TODO we might sample multiple times from the same bucket, is that a problem?
-            // pick a random non-empty bucket
-            let bucket_index = self.rng.gen_range(0..self.buckets.len());
-            let bucket = &mut self.buckets[bucket_index];
-            samples.push(bucket.sample);
-            start_indices.push(bucket.start_index);
-
-            // we can initially get less tokens than seq_len, that just means the sample was short, keep it
-            let curr_seq_len = min(self.seq_len, bucket.tokens.len());
-            assert!(curr_seq_len > 0, "Non-empty bucket is empty");
-
-            // copy tokens into batch (and remove from buffer)
-            let drain = bucket.tokens.drain(0..curr_seq_len);
-            for (i, token) in drain.enumerate() {
-                batch[(bi, i)] = token as i32;
-            }
-            bucket.start_index += curr_seq_len;
-
-            // remove potentially empty buffer
-            // if we have less than seq_len tokens left at this point they're just overflow, drop them
-            //   (they could have been sampled because of the offset, we're not introducing bias here)
-            if bucket.tokens.len() < self.seq_len {
-                bucket.tokens.clear();
-                self.empty_buffers
-                    .push_back(self.buckets.remove(bucket_index).unwrap().tokens);
-            }
+            let (sample, start_index, len) =
+                self.take_row(|i, token| batch[(bi, i)] = T::from_index(token));
+            self.stats.used_count += len;
+            self.stats.padding_count += self.seq_len - len;
+
+            lengths.push(len);
+            samples.push(sample);
+            start_indices.push(start_index);
         }
 
         let batch = Batch {
             tokens: batch,
+            lengths,
             samples,
             start_indices,
         };
@@ -169,7 +351,291 @@ impl Batcher {
         Some(batch)
     }
 
+    fn pop_dynamic_batch<T: TokenId>(
+        &mut self,
+        buffer: &mut Vec<T>,
+        pad_multiple: usize,
+        max_tokens: Option<usize>,
+    ) -> Option<Batch<T>> {
+        loop {
+            let ready = (0..self.pending.len()).find(|&class| {
+                let len = self.pending[class].len();
+                len > 0 && len >= self.dynamic_rows(class, pad_multiple, max_tokens)
+            });
+            if let Some(class) = ready {
+                return Some(self.build_dynamic_batch(buffer, class, pad_multiple, max_tokens));
+            }
+
+            if self.buckets.len() < self.bucket_count {
+                return None;
+            }
+
+            let mut tokens = vec![];
+            let (sample, start_index, _) = self.take_row(|_, token| tokens.push(token));
+            self.push_pending(
+                Row {
+                    sample,
+                    start_index,
+                    tokens,
+                },
+                pad_multiple,
+            );
+        }
+    }
+
+    fn dynamic_width(&self, class: usize, pad_multiple: usize) -> usize {
+        min(class * pad_multiple, self.seq_len)
+    }
+
+    fn dynamic_rows(&self, class: usize, pad_multiple: usize, max_tokens: Option<usize>) -> usize {
+        match max_tokens {
+            None => self.batch_size,
+            Some(max_tokens) => (max_tokens / self.dynamic_width(class, pad_multiple)).max(1),
+        }
+    }
+
+    fn push_pending(&mut self, row: Row, pad_multiple: usize) {
+        let class = row.tokens.len().div_ceil(pad_multiple);
+        if self.pending.len() <= class {
+            self.pending.resize_with(class + 1, Vec::new);
+        }
+        self.pending[class].push(row);
+    }
+
+    fn build_dynamic_batch<T: TokenId>(
+        &mut self,
+        buffer: &mut Vec<T>,
+        class: usize,
+        pad_multiple: usize,
+        max_tokens: Option<usize>,
+    ) -> Batch<T> {
+        let width = self.dynamic_width(class, pad_multiple);
+        let rows = self.dynamic_rows(class, pad_multiple, max_tokens);
+        let group = self.pending[class].drain(..rows).collect_vec();
+
+        let mut batch = padded_array(buffer, (group.len(), width));
+        for (bi, row) in group.iter().enumerate() {
+            for (i, &token) in row.tokens.iter().enumerate() {
+                batch[(bi, i)] = T::from_index(token);
+            }
+            self.stats.used_count += row.tokens.len();
+            self.stats.padding_count += width - row.tokens.len();
+        }
+
+        self.stats.batch_count += 1;
+        Batch {
+            tokens: batch,
+            lengths: group.iter().map(|r| r.tokens.len()).collect(),
+            samples: group.iter().map(|r| r.sample).collect(),
+            start_indices: group.iter().map(|r| r.start_index).collect(),
+        }
+    }
+
+    // take a row of up to `seq_len` tokens from a random bucket, passing the index and value of each token to `f`
+    //   returns the sample index, start index and number of tokens of the row
+    fn take_row(&mut self, mut f: impl FnMut(usize, usize)) -> (usize, usize, usize) {
+        // TODO we might sample multiple times from the same bucket, is that a problem?
+        // pick a random non-empty bucket
+        let bucket_index = self.rng.gen_range(0..self.buckets.len());
+        let bucket = &mut self.buckets[bucket_index];
+        let sample = bucket.sample;
+        let start_index = bucket.start_index;
+
+        // we can initially get less tokens than seq_len, that just means the sample was short, keep it
+        let curr_seq_len = min(self.seq_len, bucket.tokens.len());
+        assert!(curr_seq_len > 0, "Non-empty bucket is empty");
+
+        // copy tokens into batch (and remove from buffer)
+        let drain = bucket.tokens.drain(0..curr_seq_len);
+        for (i, token) in drain.enumerate() {
+            f(i, token);
+        }
+        bucket.start_index += curr_seq_len;
+
+        // remove potentially empty buffer
+        // if we have less than seq_len tokens left at this point they're just overflow, drop them
+        //   (they could have been sampled because of the offset, we're not introducing bias here)
+        let min_len = if self.keep_short_tails {
+            1
+        } else {
+            self.seq_len
+        };
+        if bucket.tokens.len() < min_len {
+            self.stats.dropped_tail_count += bucket.tokens.len();
+            bucket.tokens.clear();
+            self.empty_buffers
+                .push_back(self.buckets.remove(bucket_index).unwrap().tokens);
+        }
+
+        (sample, start_index, curr_seq_len)
+    }
+
     pub fn stats(&self) -> Stats {
         self.stats
     }
 }
+
+/// Take the allocation of `buffer` and fill it with padding in the given shape.
+fn padded_array<T: TokenId>(buffer: &mut Vec<T>, shape: (usize, usize)) -> Array2<T> {
+    let mut buffer = std::mem::take(buffer);
+    buffer.clear();
+    buffer.resize(shape.0 * shape.1, T::PAD);
+    Array2::from_shape_vec(shape, buffer).unwrap()
+}
+
+impl Stats {
+    /// The fraction of the tokens consumed so far that ended up in a batch instead of being dropped.
+    /// Tokens still waiting in buckets or in rows for a dynamic batch are not counted.
+    pub fn utilisation(&self) -> f64 {
+        let consumed = self.used_count + self.dropped_start_count + self.dropped_tail_count;
+        self.used_count as f64 / consumed.max(1) as f64
+    }
+
+    /// The fraction of batch positions that are padding.
+    pub fn padding_fraction(&self) -> f64 {
+        self.padding_count as f64 / (self.used_count + self.padding_count).max(1) as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::batch::{BatchShape, Batcher, TokenId};
+    use crate::schedule::{Schedule, ScheduleStep};
+
+    #[test]
+    fn waste_counts() {
+        let tokens = ["a", "b"];
+        let samples = ["ab", "abababa", "abababababababababab"];
+
+        for keep_short_tails in [false, true] {
+            // with a single bucket every sample is fully consumed before the next one is pushed
+            let mut batcher = Batcher::new(1, 4, 1, tokens).with_keep_short_tails(keep_short_tails);
+            for sample in samples {
+                batcher.push_sample(sample);
+                while batcher.pop_batch().is_some() {}
+            }
+
+            let stats = batcher.stats();
+            assert_eq!(stats.token_count, 29);
+            assert_eq!(
+                stats.used_count + stats.padding_count,
+                4 * stats.batch_count
+            );
+            assert_eq!(
+                stats.used_count + stats.dropped_start_count + stats.dropped_tail_count,
+                29
+            );
+            if keep_short_tails {
+                assert_eq!(stats.dropped_tail_count, 0);
+            }
+            assert!(stats.utilisation() > 0.0 && stats.utilisation() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn dynamic_shape() {
+        let tokens = ["a", "b"];
+        let shape = BatchShape::Dynamic {
+            pad_multiple: 4,
+            max_tokens: Some(32),
+        };
+        let mut batcher = Batcher::new(2, 16, 4, tokens)
+            .with_keep_short_tails(true)
+            .with_shape(shape);
+
+        let mut batches = vec![];
+        for i in 0..200 {
+            batcher.push_sample(&"ab".repeat(1 + i % 20));
+            while let Some(batch) = batcher.pop_batch() {
+                batches.push(batch);
+            }
+        }
+        assert!(!batches.is_empty());
+
+        for batch in &batches {
+            let (rows, width) = batch.tokens.dim();
+            assert_eq!(width % 4, 0);
+            assert!(width <= 16);
+            assert_eq!(rows, 32 / width);
+
+            let min_len = batch.lengths.iter().min().unwrap();
+            assert!(width - min_len < 4);
+            for (bi, &len) in batch.lengths.iter().enumerate() {
+                for i in 0..width {
+                    assert_eq!(batch.tokens[(bi, i)] >= 0, i < len);
+                }
+            }
+        }
+
+        // rows waiting for a batch are not counted as used yet
+        let stats = batcher.stats();
+        let batch_tokens: usize = batches
+            .iter()
+            .map(|b| b.lengths.iter().sum::<usize>())
+            .sum();
+        assert_eq!(stats.used_count, batch_tokens);
+        let batch_size: usize = batches.iter().map(|b| b.tokens.len()).sum();
+        assert_eq!(stats.used_count + stats.padding_count, batch_size);
+    }
+
+    #[test]
+    fn buffer_reuse() {
+        let tokens = ["a", "b"];
+        let mut batcher = Batcher::new(2, 8, 2, tokens).with_keep_short_tails(true);
+
+        let mut buffer: Vec<u16> = vec![];
+        let mut allocation = None;
+        for i in 0..100 {
+            batcher.push_sample(&"ab".repeat(1 + i % 10));
+            while let Some(batch) = batcher.pop_batch_into(&mut buffer) {
+                for (bi, &len) in batch.lengths.iter().enumerate() {
+                    for i in 0..8 {
+                        assert_eq!(batch.tokens[(bi, i)] == u16::PAD, i >= len);
+                    }
+                }
+
+                buffer = batch.into_buffer();
+                // only the first batch allocates
+                assert_eq!(*allocation.get_or_insert(buffer.as_ptr()), buffer.as_ptr());
+            }
+        }
+        assert!(allocation.is_some());
+    }
+
+    #[test]
+    fn schedule() {
+        let step = |batch, seq_len, batch_size| ScheduleStep {
+            batch,
+            seq_len,
+            batch_size,
+        };
+        let schedule = Schedule::new(vec![step(3, 8, 2), step(0, 4, 4)]).unwrap();
+        assert_eq!(schedule.step_at(2), Some(&step(0, 4, 4)));
+
+        for shape in [
+            BatchShape::Fixed,
+            BatchShape::Dynamic {
+                pad_multiple: 4,
+                max_tokens: None,
+            },
+        ] {
+            // start at batch 1, so the switch happens after two batches
+            let mut batcher = Batcher::new(1, 16, 4, ["a"])
+                .with_shape(shape)
+                .with_schedule(schedule.clone(), 1);
+            assert_eq!((batcher.seq_len(), batcher.batch_size()), (4, 4));
+
+            let mut shapes = vec![];
+            for _ in 0..100 {
+                batcher.push_sample(&"a".repeat(50));
+                while let Some(batch) = batcher.pop_batch() {
+                    shapes.push(batch.tokens.dim());
+                }
+            }
+
+            assert_eq!(shapes[..2], [(4, 4), (4, 4)]);
+            assert!(shapes[2..].iter().all(|&s| s == (2, 8)), "{:?}", shapes);
+            assert_eq!(batcher.batch_index(), 1 + shapes.len());
+        }
+    }
+}
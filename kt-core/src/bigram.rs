@@ -0,0 +1,396 @@
+use std::cmp::{min, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub type Count = u32;
+
+/// Decay every count is multiplied by after clipping it to `clip`.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Decay {
+    pub clip: Count,
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+/// Sparse bigram counts with lazy decay.
+///
+/// Decaying all counts is `O(1)`: each bigram remembers the step it was last updated at,
+/// and the decay steps since then are only applied when it is visited again.
+/// Every [SWEEP_INTERVAL] steps all counts are brought up to date and bigrams that reached zero are removed.
+///
+/// The top bigram is found with a lazy-deletion heap of entry snapshots. Decay never increases a count,
+/// so the count of a snapshot is an upper bound for the current count and outdated snapshots are skipped.
+pub struct BigramCounts {
+    decay: Decay,
+    step: u32,
+
+    entries: HashMap<(u32, u32), Entry>,
+    // the tokens each token forms a bigram with (in either order), used to remove tokens quickly
+    neighbors: Vec<HashSet<u32>>,
+    heap: BinaryHeap<Snapshot>,
+}
+
+/// The count, bigram and step of an entry, ordered by count and then by smallest bigram.
+type Snapshot = (Count, Reverse<(u32, u32)>, u32);
+
+pub const SWEEP_INTERVAL: u32 = 64;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    count: Count,
+    step: u32,
+}
+
+impl Decay {
+    pub fn apply(self, mut count: Count, steps: u32) -> Count {
+        for _ in 0..steps {
+            if count == 0 {
+                break;
+            }
+            let clipped = min(count, self.clip) as u64;
+            count = (clipped * self.numerator as u64 / self.denominator as u64) as Count;
+        }
+        count
+    }
+}
+
+impl BigramCounts {
+    pub fn new(decay: Decay) -> Self {
+        BigramCounts {
+            decay,
+            step: 0,
+            entries: HashMap::default(),
+            neighbors: vec![],
+            heap: BinaryHeap::default(),
+        }
+    }
+
+    /// The number of stored bigrams, including ones that decayed to zero but have not been swept yet.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, a: usize, b: usize) -> Count {
+        match self.entries.get(&key(a, b)) {
+            None => 0,
+            Some(entry) => self.decay.apply(entry.count, self.step - entry.step),
+        }
+    }
+
+    /// Increment the count of bigram `(a, b)` by `delta` and return the new count.
+    pub fn add(&mut self, a: usize, b: usize, delta: Count) -> Count {
+        let decay = self.decay;
+        let step = self.step;
+
+        let entry = self.entries.entry(key(a, b)).or_insert_with(|| {
+            let len = std::cmp::max(a, b) + 1;
+            if self.neighbors.len() < len {
+                self.neighbors.resize_with(len, HashSet::default);
+            }
+            self.neighbors[a].insert(b as u32);
+            self.neighbors[b].insert(a as u32);
+
+            Entry { count: 0, step }
+        });
+
+        entry.count = decay
+            .apply(entry.count, step - entry.step)
+            .saturating_add(delta);
+        entry.step = step;
+
+        let entry = *entry;
+        self.push_snapshot(key(a, b), entry);
+        entry.count
+    }
+
+    /// The bigram with the highest count and that count, ties are broken by the smallest bigram.
+    /// Returns `None` if all counts are zero.
+    pub fn top(&mut self) -> Option<((usize, usize), Count)> {
+        let mut best: Option<((u32, u32), Count)> = None;
+        let mut refreshed = vec![];
+
+        while let Some(&(bound, Reverse(key), step)) = self.heap.peek() {
+            // the remaining snapshots can't beat the best one, by count or by the tie break
+            if let Some((best_key, best_count)) = best {
+                if bound < best_count || (bound == best_count && key > best_key) {
+                    break;
+                }
+            }
+            self.heap.pop();
+
+            let entry = match self.entries.get_mut(&key) {
+                Some(entry) if *entry == (Entry { count: bound, step }) => entry,
+                // outdated or duplicate snapshot
+                _ => continue,
+            };
+
+            entry.count = self.decay.apply(entry.count, self.step - entry.step);
+            entry.step = self.step;
+            refreshed.push((key, *entry));
+
+            let better = match best {
+                None => true,
+                Some((best_key, best_count)) => {
+                    entry.count > best_count || (entry.count == best_count && key < best_key)
+                }
+            };
+            if better {
+                best = Some((key, entry.count));
+            }
+        }
+
+        for (key, entry) in refreshed {
+            self.push_snapshot(key, entry);
+        }
+
+        best.filter(|&(_, count)| count > 0)
+            .map(|((a, b), count)| ((a as usize, b as usize), count))
+    }
+
+    pub fn clear(&mut self, a: usize, b: usize) {
+        self.remove_entry(key(a, b));
+    }
+
+    /// Decay all counts by a single step.
+    pub fn decay(&mut self) {
+        self.step += 1;
+        if self.step.is_multiple_of(SWEEP_INTERVAL) {
+            self.sweep();
+        }
+    }
+
+    /// Remove all bigrams containing token `a` and rename token `last` to `a`,
+    /// matching [Vec::swap_remove] on the token list.
+    pub fn swap_remove_token(&mut self, a: usize, last: usize) {
+        assert!(a <= last);
+        if self.neighbors.len() <= last {
+            self.neighbors.resize_with(last + 1, HashSet::default);
+        }
+
+        let (a, last) = (a as u32, last as u32);
+
+        for n in std::mem::take(&mut self.neighbors[a as usize]) {
+            self.entries.remove(&(a, n));
+            self.entries.remove(&(n, a));
+            self.neighbors[n as usize].remove(&a);
+        }
+
+        if a != last {
+            for n in std::mem::take(&mut self.neighbors[last as usize]) {
+                let n_new = if n == last { a } else { n };
+
+                if let Some(entry) = self.entries.remove(&(last, n)) {
+                    self.entries.insert((a, n_new), entry);
+                    self.push_snapshot((a, n_new), entry);
+                }
+                if let Some(entry) = self.entries.remove(&(n, last)) {
+                    self.entries.insert((n_new, a), entry);
+                    self.push_snapshot((n_new, a), entry);
+                }
+
+                if n != last {
+                    let neighbors = &mut self.neighbors[n as usize];
+                    neighbors.remove(&last);
+                    neighbors.insert(a);
+                }
+                self.neighbors[a as usize].insert(n_new);
+            }
+        }
+
+        self.neighbors.truncate(last as usize);
+    }
+
+    /// Apply all pending decay and remove bigrams that reached zero.
+    fn sweep(&mut self) {
+        let decay = self.decay;
+        let step = self.step;
+
+        let mut zero = vec![];
+        for (&key, entry) in &mut self.entries {
+            entry.count = decay.apply(entry.count, step - entry.step);
+            entry.step = step;
+            if entry.count == 0 {
+                zero.push(key);
+            }
+        }
+
+        for key in zero {
+            self.remove_entry(key);
+        }
+
+        self.rebuild_heap();
+    }
+
+    fn push_snapshot(&mut self, key: (u32, u32), entry: Entry) {
+        self.heap.push((entry.count, Reverse(key), entry.step));
+
+        // drop outdated snapshots once they dominate the heap
+        if self.heap.len() > 2 * self.entries.len() + 1024 {
+            self.rebuild_heap();
+        }
+    }
+
+    fn rebuild_heap(&mut self) {
+        self.heap = self
+            .entries
+            .iter()
+            .map(|(&key, entry)| (entry.count, Reverse(key), entry.step))
+            .collect();
+    }
+
+    fn remove_entry(&mut self, (a, b): (u32, u32)) {
+        if self.entries.remove(&(a, b)).is_some() && !self.entries.contains_key(&(b, a)) {
+            self.neighbors[a as usize].remove(&b);
+            self.neighbors[b as usize].remove(&a);
+        }
+    }
+}
+
+fn key(a: usize, b: usize) -> (u32, u32) {
+    (a as u32, b as u32)
+}
+
+/// The serialized form of [BigramCounts], the neighbors are rebuilt when loading.
+#[derive(Serialize, Deserialize)]
+struct BigramCountsData {
+    decay: Decay,
+    step: u32,
+    entries: Vec<(u32, u32, Entry)>,
+}
+
+impl Serialize for BigramCounts {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut entries = self
+            .entries
+            .iter()
+            .map(|(&(a, b), &entry)| (a, b, entry))
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|&(a, b, _)| (a, b));
+
+        let data = BigramCountsData {
+            decay: self.decay,
+            step: self.step,
+            entries,
+        };
+        data.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BigramCounts {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = BigramCountsData::deserialize(deserializer)?;
+
+        let mut result = BigramCounts::new(data.decay);
+        result.step = data.step;
+        for (a, b, entry) in data.entries {
+            result.add(a as usize, b as usize, 0);
+            result.entries.insert((a, b), entry);
+        }
+        result.rebuild_heap();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    use crate::bigram::{BigramCounts, Count, Decay};
+
+    const DECAY: Decay = Decay {
+        clip: 100,
+        numerator: 900,
+        denominator: 1000,
+    };
+
+    #[test]
+    fn lazy_decay_matches_eager() {
+        let mut counts = BigramCounts::new(DECAY);
+        counts.add(0, 1, 150);
+        counts.add(1, 0, 7);
+
+        let mut expected = [150, 7];
+        for _ in 0..200 {
+            counts.decay();
+            expected = expected.map(|c| DECAY.apply(c, 1));
+
+            assert_eq!(counts.get(0, 1), expected[0]);
+            assert_eq!(counts.get(1, 0), expected[1]);
+        }
+
+        // swept once they reach zero
+        assert!(counts.is_empty());
+    }
+
+    #[test]
+    fn swap_remove_token() {
+        let mut counts = BigramCounts::new(DECAY);
+        counts.add(0, 1, 1);
+        counts.add(1, 3, 2);
+        counts.add(3, 3, 3);
+        counts.add(3, 0, 4);
+        counts.add(2, 1, 5);
+
+        counts.swap_remove_token(1, 3);
+
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts.get(1, 1), 3);
+        assert_eq!(counts.get(1, 0), 4);
+        assert_eq!(counts.get(2, 1), 0);
+
+        counts.add(2, 0, 6);
+        counts.swap_remove_token(2, 2);
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts.get(2, 0), 0);
+    }
+
+    fn brute_force_top(counts: &BigramCounts, tokens: usize) -> Option<((usize, usize), Count)> {
+        let mut best = None;
+        for a in 0..tokens {
+            for b in 0..tokens {
+                let count = counts.get(a, b);
+                // strictly greater keeps the smallest bigram on ties
+                if count > 0 && best.is_none_or(|(_, c)| count > c) {
+                    best = Some(((a, b), count));
+                }
+            }
+        }
+        best
+    }
+
+    #[test]
+    fn top_matches_brute_force() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut counts = BigramCounts::new(DECAY);
+        let mut tokens = 12;
+
+        for i in 0..5000 {
+            match rng.gen_range(0..10) {
+                0 => counts.decay(),
+                1 => {
+                    // merge the top bigram into a new token
+                    if let Some(((a, b), _)) = counts.top() {
+                        counts.clear(a, b);
+                        tokens += 1;
+                    }
+                }
+                2 if tokens > 4 => {
+                    counts.swap_remove_token(rng.gen_range(0..tokens), tokens - 1);
+                    tokens -= 1;
+                }
+                _ => {
+                    let (a, b) = (rng.gen_range(0..tokens), rng.gen_range(0..tokens));
+                    counts.add(a, b, rng.gen_range(0..30));
+                }
+            }
+
+            assert_eq!(counts.top(), brute_force_top(&counts, tokens), "step {}", i);
+        }
+    }
+}
@@ -1,32 +1,59 @@
-extern crate core;
+use std::path::PathBuf;
 
-use std::fs::File;
+use clap::Parser;
 
-use serde::Deserialize;
-
-use kt_core::batch::Batcher;
+use kt_core::batch::{BatchShape, Batcher};
+use kt_core::pipeline::PipelineArgs;
 use kt_core::sample::SampleReader;
+use kt_core::vocab::Vocab;
+
+/// Print the shapes of the first few batches generated from a data file.
+#[derive(Debug, Parser)]
+struct Args {
+    vocab: PathBuf,
+    input: PathBuf,
+
+    #[clap(long, default_value_t = 4)]
+    batch_size: usize,
+    #[clap(long, default_value_t = 8)]
+    seq_len: usize,
+    /// Defaults to twice the batch size.
+    #[clap(long)]
+    bucket_count: Option<usize>,
+    /// The number of batches to print.
+    #[clap(long, default_value_t = 10)]
+    max_batches: usize,
+    /// Pad the tokens left at the end of samples instead of dropping them.
+    #[clap(long)]
+    keep_short_tails: bool,
+    /// Group rows by length and round the batch width up to this multiple instead of always using the sequence length.
+    #[clap(long)]
+    pad_multiple: Option<usize>,
+    /// With `--pad-multiple`, fill each batch with this many tokens instead of using the batch size.
+    #[clap(long, requires = "pad_multiple")]
+    max_tokens: Option<usize>,
 
-#[derive(Debug, Deserialize)]
-struct Tokens {
-    tokens: Vec<Vec<u8>>,
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
 }
 
 fn main() -> std::io::Result<()> {
-    // let path = r"C:\Users\Karel\Desktop\the-pile\00.jsonl.zst";
-    let path = r"C:\Users\Karel\Desktop\the-pile\test.jsonl.zst";
-    let path_tokens = "ignored/tokens.json";
+    let args = Args::parse();
+    let bucket_count = args.bucket_count.unwrap_or(2 * args.batch_size);
 
-    let batch_size = 4;
-    let seq_len = 8;
-    let bucket_count = 2 * batch_size;
-
-    let all_tokens: Tokens = serde_json::from_str(&std::fs::read_to_string(path_tokens)?)?;
-    let mut batcher = Batcher::new(batch_size, seq_len, bucket_count, all_tokens.tokens);
+    let vocab = Vocab::load(&args.vocab)?;
+    let mut batcher = Batcher::new(args.batch_size, args.seq_len, bucket_count, vocab.tokens)
+        .with_keep_short_tails(args.keep_short_tails);
+    if let Some(pad_multiple) = args.pad_multiple {
+        batcher = batcher.with_shape(BatchShape::Dynamic {
+            pad_multiple,
+            max_tokens: args.max_tokens,
+        });
+    }
 
-    let file = File::open(path)?;
+    let pipeline = args.pipeline.build()?;
 
-    for sample in SampleReader::new_decode(file, true, true)? {
+    for sample in SampleReader::open(&args.input, pipeline, vocab.normalization)? {
         let sample = sample?;
 
         batcher.push_sample(&sample.text);
@@ -34,21 +61,30 @@ fn main() -> std::io::Result<()> {
         // yield as many batches as possible
         while let Some(batch) = batcher.pop_batch() {
             println!(
-                "Yielding batch with\n    shape {:?},\n    samples {:?},\n    start_indices {:?}",
+                "Yielding batch with\n    shape {:?},\n    lengths {:?},\n    samples {:?},\n    start_indices {:?}",
                 batch.tokens.shape(),
+                batch.lengths,
                 batch.samples,
                 batch.start_indices,
             );
 
-            if batcher.stats().batch_count >= 10 {
+            if batcher.stats().batch_count >= args.max_batches {
                 break;
             }
         }
 
-        if batcher.stats().batch_count >= 10 {
+        if batcher.stats().batch_count >= args.max_batches {
             break;
         }
     }
 
+    let stats = batcher.stats();
+    println!("{:?}", stats);
+    println!(
+        "Utilisation {:.4}, padding fraction {:.4}",
+        stats.utilisation(),
+        stats.padding_fraction()
+    );
+
     Ok(())
 }
@@ -4,6 +4,8 @@ use std::path::PathBuf;
 
 use clap::Parser;
 
+use kt_core::normalize::Normalization;
+use kt_core::pipeline::PipelineArgs;
 use kt_core::sample::SampleReader;
 
 #[derive(Parser)]
@@ -15,20 +17,26 @@ struct Args {
     max_samples: Option<usize>,
     #[clap(long)]
     max_bytes: Option<usize>,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+    #[clap(long, default_value = "nfc")]
+    normalization: Normalization,
 }
 
 fn main() -> std::io::Result<()> {
     let args = Args::parse();
     assert_ne!(args.input, args.output);
 
-    let file = File::open(&args.input)?;
     let mut writer = BufWriter::new(File::create(&args.output)?);
 
     let mut samples = 0;
     let mut bytes = 0;
     let mut lines = 0;
 
-    for sample in SampleReader::new_decode(file, true, true)? {
+    let mut reader = SampleReader::open(&args.input, args.pipeline.build()?, args.normalization)?;
+
+    for sample in &mut reader {
         let sample = sample?;
 
         writer.write_all(sample.text.as_bytes())?;
@@ -45,10 +53,11 @@ fn main() -> std::io::Result<()> {
     writer.flush()?;
 
     println!("Decompressed {samples} samples, {lines} lines, {bytes} bytes");
+    print!("Pipeline:\n{}", reader.pipeline());
 
     Ok(())
 }
 
 fn below_limit(x: usize, max: Option<usize>) -> bool {
-    max.map_or(true, |max| x < max)
+    max.is_none_or(|max| x < max)
 }
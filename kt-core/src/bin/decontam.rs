@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::Parser;
+use zstd::Encoder;
+
+use kt_core::decontam::{DecontamConfig, NgramIndex};
+use kt_core::sample::SampleLines;
+
+/// Find training samples that overlap with the eval sets, optionally writing the clean samples to a new zstd JSONL file.
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+
+    #[clap(flatten)]
+    config: DecontamConfig,
+
+    /// Write the lines of all uncontaminated samples to this file.
+    #[clap(long)]
+    output: Option<PathBuf>,
+    /// The zstd compression level of the output.
+    #[clap(long, default_value_t = 3)]
+    level: i32,
+    /// The number of contaminated samples to print.
+    #[clap(long, default_value_t = 10)]
+    examples: usize,
+}
+
+#[derive(Default)]
+struct SourceCounts {
+    samples: u64,
+    hits: u64,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    if let Some(output) = &args.output {
+        assert!(!args.inputs.contains(output));
+    }
+
+    let index = NgramIndex::load(&args.config)?;
+    println!(
+        "Loaded {} distinct {}-grams from {} eval sources",
+        index.len(),
+        args.config.ngram,
+        index.sources().len()
+    );
+
+    let mut writer = match &args.output {
+        None => None,
+        Some(output) => Some(Encoder::new(
+            BufWriter::new(File::create(output)?),
+            args.level,
+        )?),
+    };
+
+    let mut samples = 0;
+    let mut contaminated = 0;
+    let mut examples = 0;
+    let mut per_source: Vec<SourceCounts> = (0..index.sources().len())
+        .map(|_| SourceCounts::default())
+        .collect();
+    // (contaminated, total) samples for each training set
+    let mut per_set: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+
+    for input in &args.inputs {
+        println!("Reading {:?}", input);
+        for line in SampleLines::open(input)? {
+            let (line, sample) = line?;
+            samples += 1;
+
+            let contamination = index.check(&sample.text);
+            let is_contaminated = contamination.hits >= args.config.min_hits.max(1);
+
+            let set_counts = per_set.entry(sample.meta.pile_set_name).or_default();
+            set_counts.1 += 1;
+
+            if is_contaminated {
+                contaminated += 1;
+                set_counts.0 += 1;
+                for &(source, hits) in &contamination.source_hits {
+                    per_source[source].samples += 1;
+                    per_source[source].hits += hits as u64;
+                }
+
+                if examples < args.examples {
+                    examples += 1;
+                    let range = contamination.first.clone().unwrap();
+                    println!(
+                        "  sample {} has {} hits, first: {:?}",
+                        samples - 1,
+                        contamination.hits,
+                        &sample.text[range]
+                    );
+                }
+            } else if let Some(writer) = &mut writer {
+                writer.write_all(line.as_bytes())?;
+                writer.write_all(b"\n")?;
+            }
+        }
+    }
+
+    if let Some(writer) = writer {
+        writer.finish()?.flush()?;
+    }
+
+    println!("Contaminated {} of {} samples", contaminated, samples);
+    println!("Per eval source: (contaminated samples, distinct n-gram hits)");
+    for (name, counts) in index.sources().iter().zip(&per_source) {
+        println!("  {}: {}, {}", name, counts.samples, counts.hits);
+    }
+    println!("Per training set: (contaminated samples / samples)");
+    for (name, (contaminated, total)) in &per_set {
+        println!("  {}: {} / {}", name, contaminated, total);
+    }
+
+    Ok(())
+}
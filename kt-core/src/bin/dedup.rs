@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::Parser;
+use zstd::Encoder;
+
+use kt_core::dedup::{DedupConfig, Deduplicator, Duplicate};
+use kt_core::sample::SampleLines;
+
+/// Remove exact and near-duplicate samples, writing the remaining lines unchanged to a new zstd JSONL file.
+/// Samples are compared across all inputs.
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+    #[clap(long)]
+    output: PathBuf,
+
+    #[clap(flatten)]
+    config: DedupConfig,
+
+    /// The zstd compression level of the output.
+    #[clap(long, default_value_t = 3)]
+    level: i32,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    assert!(!args.inputs.contains(&args.output));
+
+    let mut dedup = Deduplicator::new(args.config)?;
+    let mut writer = Encoder::new(BufWriter::new(File::create(&args.output)?), args.level)?;
+
+    let mut samples = 0;
+    let mut exact = 0;
+    let mut near = 0;
+
+    for input in &args.inputs {
+        println!("Reading {:?}", input);
+        for line in SampleLines::open(input)? {
+            let (line, sample) = line?;
+            samples += 1;
+
+            match dedup.check(&sample.text) {
+                None => {
+                    writer.write_all(line.as_bytes())?;
+                    writer.write_all(b"\n")?;
+                }
+                Some(Duplicate::Exact) => exact += 1,
+                Some(Duplicate::Near) => near += 1,
+            }
+
+            if samples % 100_000 == 0 {
+                println!(
+                    "  {} samples, {} exact and {} near duplicates, memory {} MB",
+                    samples,
+                    exact,
+                    near,
+                    dedup.memory_usage() / 1024 / 1024
+                );
+            }
+        }
+    }
+
+    writer.finish()?.flush()?;
+
+    println!(
+        "Kept {} of {} samples, removed {} exact and {} near duplicates",
+        samples - exact - near,
+        samples,
+        exact,
+        near
+    );
+    Ok(())
+}
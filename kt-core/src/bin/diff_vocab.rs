@@ -0,0 +1,108 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use kt_core::evaluate::{diff_vocabs, CompressionDiff, VocabDiff, VocabEvaluator};
+use kt_core::normalize::Normalization;
+use kt_core::pipeline::PipelineArgs;
+use kt_core::sample::SampleReader;
+use kt_core::vocab::Vocab;
+
+#[derive(Debug, Parser)]
+struct Args {
+    vocab_a: PathBuf,
+    vocab_b: PathBuf,
+    /// Reference corpus used to rank tokens and measure compression.
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Maximum number of samples to read from each input.
+    #[clap(long)]
+    max_samples: Option<usize>,
+    /// Number of tokens to list for each category.
+    #[clap(long, default_value_t = 20)]
+    top: usize,
+    /// Print the full diff as JSON instead of the human-readable summary.
+    #[clap(long)]
+    json: bool,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+
+    let vocab_a = Vocab::load(&args.vocab_a)?;
+    let vocab_b = Vocab::load(&args.vocab_b)?;
+    if vocab_a.normalization != vocab_b.normalization {
+        println!(
+            "Warning: comparing vocabs with different normalization {} and {}",
+            vocab_a.normalization, vocab_b.normalization
+        );
+    }
+
+    let mut evaluator_a = VocabEvaluator::new(vocab_a.tokens, vocab_a.normalization);
+    let mut evaluator_b = VocabEvaluator::new(vocab_b.tokens, vocab_b.normalization);
+
+    for input in &args.inputs {
+        let reader = SampleReader::open(input, args.pipeline.build()?, Normalization::NONE)?;
+        for sample in reader.take(args.max_samples.unwrap_or(usize::MAX)) {
+            let sample = sample?;
+            evaluator_a.add_sample(&sample);
+            evaluator_b.add_sample(&sample);
+        }
+    }
+
+    let diff = diff_vocabs(&evaluator_a, &evaluator_b, args.top)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        print_diff(&diff, args.top);
+    }
+
+    Ok(())
+}
+
+fn print_diff(diff: &VocabDiff, top: usize) {
+    println!(
+        "Shared tokens {}, added {}, removed {}",
+        diff.shared,
+        diff.added.len(),
+        diff.removed.len()
+    );
+
+    println!("Compression: (bytes/token a -> b)");
+    print_compression("total", &diff.total);
+    for (name, compression) in &diff.per_set {
+        print_compression(name, compression);
+    }
+
+    println!("Most used added tokens:");
+    for usage in diff.added.iter().take(top) {
+        println!("  {:?}: {}", usage.token, usage.count);
+    }
+    println!("Most used removed tokens:");
+    for usage in diff.removed.iter().take(top) {
+        println!("  {:?}: {}", usage.token, usage.count);
+    }
+
+    println!("Largest rank changes: (rank a -> b, count a -> b)");
+    for change in &diff.rank_changes {
+        println!(
+            "  {:?}: {} -> {}, {} -> {}",
+            change.token, change.rank_a, change.rank_b, change.count_a, change.count_b
+        );
+    }
+}
+
+fn print_compression(name: &str, compression: &CompressionDiff) {
+    println!(
+        "  {}: {:.3} -> {:.3} ({:+.2}%)",
+        name,
+        compression.a.bytes_per_token,
+        compression.b.bytes_per_token,
+        compression.bytes_per_token_change * 100.0
+    );
+}
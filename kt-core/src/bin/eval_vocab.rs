@@ -0,0 +1,104 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use kt_core::evaluate::{Compression, VocabEvaluator, VocabReport};
+use kt_core::normalize::Normalization;
+use kt_core::pipeline::PipelineArgs;
+use kt_core::sample::SampleReader;
+use kt_core::vocab::Vocab;
+
+#[derive(Debug, Parser)]
+struct Args {
+    vocab: PathBuf,
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Maximum number of samples to read from each input.
+    #[clap(long)]
+    max_samples: Option<usize>,
+    /// Number of most common and rarest tokens to list.
+    #[clap(long, default_value_t = 20)]
+    top: usize,
+    /// Print the report as JSON instead of the human-readable summary.
+    #[clap(long)]
+    json: bool,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+
+    let vocab = Vocab::load(&args.vocab)?;
+    let mut evaluator = VocabEvaluator::new(vocab.tokens, vocab.normalization);
+
+    for input in &args.inputs {
+        let reader = SampleReader::open(input, args.pipeline.build()?, Normalization::NONE)?;
+        for sample in reader.take(args.max_samples.unwrap_or(usize::MAX)) {
+            evaluator.add_sample(&sample?);
+        }
+    }
+
+    let report = evaluator.report(args.top);
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report);
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &VocabReport) {
+    println!(
+        "Vocab size {}, unused tokens {} ({:.2}%)",
+        report.vocab_size,
+        report.unused_tokens,
+        report.unused_fraction * 100.0
+    );
+
+    println!("Compression:");
+    print_compression("total", &report.total);
+    for (name, compression) in &report.per_set {
+        print_compression(name, compression);
+    }
+
+    println!("Token length histogram: (length: vocab tokens, used tokens)");
+    for (length, count) in &report.length_histogram {
+        println!(
+            "  {}: {}, {}",
+            length, count, report.used_length_histogram[length]
+        );
+    }
+
+    println!("Most common tokens:");
+    for usage in &report.most_common {
+        println!(
+            "  {:?}: {} ({:.4}%)",
+            usage.token,
+            usage.count,
+            usage.fraction * 100.0
+        );
+    }
+    println!("Rarest used tokens:");
+    for usage in &report.rarest {
+        println!("  {:?}: {}", usage.token, usage.count);
+    }
+}
+
+fn print_compression(name: &str, compression: &Compression) {
+    let counts = &compression.counts;
+    println!(
+        "  {}: {} samples, {} tokens, {:.3} bytes/token, {:.3} chars/token, {:.3} words/token, {} bytes skipped",
+        name,
+        counts.samples,
+        counts.tokens,
+        compression.bytes_per_token,
+        compression.chars_per_token,
+        compression.words_per_token,
+        counts.skipped_bytes
+    );
+}
@@ -1,20 +1,23 @@
-use std::cmp::{min, Reverse};
+use std::cmp::Reverse;
 use std::fmt::{Debug, Formatter};
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
+use aho_corasick::AhoCorasick;
 use clap::Parser;
 use itertools::Itertools;
-use ndarray::{s, Array2, Zip};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use kt_core::batch::build_tokenizer;
-use kt_core::iter::FlatRepeatResult;
+use kt_core::bigram::{BigramCounts, Count, Decay};
+use kt_core::normalize::Normalization;
+use kt_core::pipeline::{PipelineArgs, Stage};
+use kt_core::prune::prune_vocab;
 use kt_core::sample::SampleReader;
 
-#[derive(Debug, Parser, Serialize)]
+#[derive(Debug, Parser, Serialize, Deserialize)]
 struct Args {
     input: PathBuf,
     output: PathBuf,
@@ -36,16 +39,93 @@ struct Args {
 
     #[clap(long, default_value_t = 0.99)]
     count_decay: f32,
+
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pipeline: PipelineArgs,
+
+    /// Normalization applied to the training text, stored in the output vocab.
+    #[clap(long, default_value = "nfc")]
+    normalization: Normalization,
+
+    /// Remove obsolete merged tokens every time this many tokens have been added.
+    #[clap(long, requires = "prune_input")]
+    prune_interval: Option<usize>,
+    /// Held-out file used to measure token usage when pruning, required for pruning and must not be the input file.
+    #[clap(long)]
+    prune_input: Option<PathBuf>,
+    #[clap(long, default_value_t = 1000)]
+    prune_samples: usize,
+    /// Tokens used for at most this fraction of the held-out tokens are removed.
+    #[clap(long, default_value_t = 1e-6)]
+    prune_max_usage: f64,
+
+    /// Number of samples tokenized in parallel at once, this only affects performance and not the result.
+    #[clap(long, default_value_t = 64)]
+    block_samples: usize,
+    /// Number of counting threads, defaults to the available parallelism.
+    /// This does not affect the result.
+    #[clap(long)]
+    threads: Option<usize>,
+
+    /// Path to periodically write the full trainer state to.
+    #[clap(long)]
+    checkpoint: Option<PathBuf>,
+    /// Write a checkpoint every time this many tokens have been added.
+    #[clap(long, default_value_t = 16)]
+    checkpoint_interval: usize,
+    /// Continue training from the checkpoint instead of starting from scratch.
+    #[clap(long, requires = "checkpoint")]
+    resume: bool,
+}
+
+impl Args {
+    // the decay factor as a fraction with this denominator
+    const DECAY_DENOMINATOR: u32 = 1000;
+
+    fn decay_numerator(&self) -> u32 {
+        (self.count_decay * Self::DECAY_DENOMINATOR as f32) as u32
+    }
+
+    fn bigram_decay(&self) -> Decay {
+        Decay {
+            clip: self.threshold_count,
+            numerator: self.decay_numerator(),
+            denominator: Self::DECAY_DENOMINATOR,
+        }
+    }
+
+    fn decay_unigram(&self, count: Count) -> Count {
+        (count * self.decay_numerator() / Self::DECAY_DENOMINATOR) as Count
+    }
+
+    /// The args that can influence the resulting tokens,
+    /// these have to match between a checkpoint and a resumed run.
+    fn training_args(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        let map = value.as_object_mut().unwrap();
+        for key in [
+            "output",
+            "debug_path",
+            "threads",
+            "checkpoint",
+            "checkpoint_interval",
+            "resume",
+        ] {
+            map.remove(key);
+        }
+        value
+    }
 }
 
 #[derive(Debug, Serialize)]
 struct Output {
     args: Args,
+    pipeline: Vec<Stage>,
+    normalization: Normalization,
     tokens: Vec<Vec<u8>>,
 }
 
-// TODO remove tokens that are no longer used since they became part of the larger token?
-//    eg. maybe we don't need "havi" any more after we have "having"
 // TODO prevent merging between punctuation and word characters (and digits?)
 //    eg. "tion." is not great
 //    be careful to still allow things like "we'll"?
@@ -55,210 +135,524 @@ struct Output {
 //    and the rule is "every token that does not start with "_" and is not preceded by a whitespace token was preceded by a single space
 //    meh, all of this is messy, ad-hoc and most importantly non-zero!
 
-type Count = u32;
+#[derive(Serialize, Deserialize)]
+struct State {
+    tokens: Vec<Vec<u8>>,
+    is_whitespace: Vec<bool>,
+    bigram_count: BigramCounts,
+    unigram_count: Vec<Count>,
+    has_been_merged: Vec<bool>,
+}
 
-fn main() -> std::io::Result<()> {
-    let args: Args = Args::parse();
-    println!("Args: {:#?}", args);
+/// Everything besides [State] that is needed to resume training.
+#[derive(Default, Serialize, Deserialize)]
+struct Progress {
+    // position in the input file
+    pass: usize,
+    sample_index: usize,
+
+    tokens_since_add: usize,
+    samples_since_add: u32,
+    // the most frequent bigram incremented since the last added token
+    top_count: Count,
+    top_index: Option<(usize, usize)>,
+    tokens_added: usize,
+    drops_applied: usize,
+    tokens_since_prune: usize,
+    pruned_slots: usize,
+
+    dropped_tokens: Vec<(Vec<u8>, Count)>,
+}
 
-    assert_eq!("zst", args.input.extension().unwrap());
-    assert_eq!("json", args.output.extension().unwrap());
-    std::fs::create_dir_all(args.output.parent().unwrap())?;
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    args: &'a Args,
+    state: &'a State,
+    progress: &'a Progress,
+}
+
+#[derive(Deserialize)]
+struct Checkpoint {
+    args: Args,
+    state: State,
+    progress: Progress,
+}
 
-    let max_tokens = args.max_tokens;
-    let max_drops = args.max_drops;
-    let threshold_drop_factor = args.threshold_drop_factor;
-    let threshold_count = args.threshold_count;
-    let threshold_samples = args.threshold_samples;
-    assert!(threshold_count < Count::MAX);
-
-    assert!((0.0..1.0).contains(&args.count_decay));
-    let count_decay_numerator: u32 = (args.count_decay * 1000.0) as u32;
-    let count_decay_denominator: u32 = 1000;
-
-    // start with a token for each possible byte
-    let mut tokens = (0..u8::MAX).map(|x| vec![x]).collect_vec();
-    let mut is_whitespace = (0..u8::MAX)
-        .map(|c| (c as char).is_whitespace())
-        .collect_vec();
-    let forced_token_count = u8::MAX as usize;
-
-    let mut aho = build_tokenizer(&tokens);
-
-    let mut bigram_count: Array2<Count> = Array2::zeros((max_tokens, max_tokens));
-    let mut unigram_count: Vec<Count> = vec![0; tokens.len()];
-    let mut has_been_merged: Vec<bool> = vec![false; tokens.len()];
-
-    let mut tokens_since_add = 0;
-    let mut samples_since_add = 0;
-    let mut drops_applied = 0;
-    let mut top_count = 0;
-    let mut top_index = None;
-    let mut prev_time = Instant::now();
-
-    let mut dropped_tokens = vec![];
-
-    let sample_iter = FlatRepeatResult::new(|| -> std::io::Result<_> {
-        println!("Start decoding from start of file");
-        Ok(SampleReader::new_decode(
-            File::open(&args.input)?,
-            true,
-            true,
-        )?)
-    });
-
-    for sample in sample_iter {
-        let sample = sample??;
-        samples_since_add += 1;
+impl State {
+    /// Remove the token at `index` by swapping it with the last token.
+    fn remove_token(&mut self, index: usize) -> (Vec<u8>, Count) {
+        let a = index;
+        let b = self.tokens.len() - 1;
 
-        let mut prev_token: Option<usize> = None;
+        let token = self.tokens.swap_remove(a);
+        let count = self.unigram_count.swap_remove(a);
+        self.is_whitespace.swap_remove(a);
+        self.has_been_merged.swap_remove(a);
 
-        for x in aho.find_iter(&sample.text) {
-            let curr_token = x.pattern();
+        self.bigram_count.swap_remove_token(a, b);
 
-            tokens_since_add += 1;
-            unigram_count[curr_token] += 1;
+        (token, count)
+    }
+
+    /// Remove merged tokens that are (almost) never used when tokenizing the held-out samples.
+    /// Returns the removed tokens with their held-out usage.
+    fn prune(
+        &mut self,
+        holdout: &[String],
+        forced_token_count: usize,
+        max_usage_fraction: f64,
+    ) -> Vec<(Vec<u8>, u64)> {
+        let obsolete = prune_vocab(
+            &self.tokens,
+            holdout.iter().map(|s| s.as_str()),
+            max_usage_fraction,
+        );
+
+        // remove in reverse order so the swapped-in tokens are never ones we still have to remove
+        obsolete
+            .into_iter()
+            .rev()
+            .filter(|&(i, _)| i >= forced_token_count)
+            .map(|(i, usage)| (self.remove_token(i).0, usage))
+            .collect()
+    }
+}
+
+/// Tokenize `texts`, split into one shard per thread.
+/// The tokens are returned in sample order so the counting afterwards is deterministic.
+fn tokenize_block(aho: &AhoCorasick, texts: &[String], threads: usize) -> Vec<Vec<usize>> {
+    let chunk_size = texts.len().div_ceil(threads).max(1);
+
+    std::thread::scope(|s| {
+        let handles = texts
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|text| aho.find_iter(text).map(|x| x.pattern()).collect_vec())
+                        .collect_vec()
+                })
+            })
+            .collect_vec();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    })
+}
+
+fn save_checkpoint(
+    path: &Path,
+    args: &Args,
+    state: &State,
+    progress: &Progress,
+) -> std::io::Result<()> {
+    // write to a temporary file first so a crash while writing does not corrupt the previous checkpoint
+    let path_tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&path_tmp)?);
+    let checkpoint = CheckpointRef {
+        args,
+        state,
+        progress,
+    };
+    serde_json::to_writer(&mut writer, &checkpoint)?;
+    writer.flush()?;
+    drop(writer);
+
+    std::fs::rename(path_tmp, path)
+}
+
+fn load_checkpoint(path: &Path, args: &Args) -> std::io::Result<(State, Progress)> {
+    let checkpoint: Checkpoint = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+    if checkpoint.args.training_args() != args.training_args() {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Args do not match checkpoint {:?}, checkpoint was created with {:#?}",
+                path, checkpoint.args
+            ),
+        ));
+    }
+
+    Ok((checkpoint.state, checkpoint.progress))
+}
+
+/// The training loop, separate from reading the input and writing the output.
+struct Trainer<'a> {
+    args: &'a Args,
+    threads: usize,
+    // token for each possible byte
+    forced_token_count: usize,
+    holdout: Vec<String>,
+
+    state: State,
+    progress: Progress,
+    aho: AhoCorasick,
+    prev_time: Instant,
+}
+
+impl<'a> Trainer<'a> {
+    /// Start from scratch, or from `resume` if given.
+    fn new(
+        args: &'a Args,
+        threads: usize,
+        holdout: Vec<String>,
+        resume: Option<(State, Progress)>,
+    ) -> Self {
+        assert!(args.threshold_count < Count::MAX);
+        assert!(args.block_samples > 0);
+        assert!(args.checkpoint_interval > 0);
+        assert!((0.0..1.0).contains(&args.count_decay));
+        assert!(threads > 0);
+
+        let (state, progress) = resume.unwrap_or_else(|| {
+            let tokens = (0..u8::MAX).map(|x| vec![x]).collect_vec();
+            let state = State {
+                is_whitespace: (0..u8::MAX)
+                    .map(|c| (c as char).is_whitespace())
+                    .collect_vec(),
+                bigram_count: BigramCounts::new(args.bigram_decay()),
+                unigram_count: vec![0; tokens.len()],
+                has_been_merged: vec![false; tokens.len()],
+                tokens,
+            };
+            (state, Progress::default())
+        });
+
+        Trainer {
+            args,
+            threads,
+            forced_token_count: u8::MAX as usize,
+            holdout,
+            aho: build_tokenizer(&state.tokens),
+            state,
+            progress,
+            prev_time: Instant::now(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.state.tokens.len() >= self.args.max_tokens
+    }
+
+    /// Run a pass over `samples`, skipping the ones that have already been processed in the current pass.
+    /// `after_add` is called after every added token, eg. to write checkpoints, and can stop training by returning false.
+    /// Returns false if training stopped before the end of the pass, because it is done or because of `after_add`.
+    fn run_pass(
+        &mut self,
+        samples: impl Iterator<Item = std::io::Result<String>>,
+        mut after_add: impl FnMut(&Self) -> std::io::Result<bool>,
+    ) -> std::io::Result<bool> {
+        println!(
+            "Start decoding pass {} from sample {}",
+            self.progress.pass, self.progress.sample_index
+        );
+        let mut samples = samples.skip(self.progress.sample_index);
+        let mut block: Vec<String> = vec![];
+
+        loop {
+            for sample in samples.by_ref().take(self.args.block_samples - block.len()) {
+                block.push(sample?);
+            }
+            if block.is_empty() {
+                break;
+            }
+
+            match self.process_block(&block) {
+                None => block.clear(),
+                Some(processed) => {
+                    // the remaining samples were tokenized before the token was added
+                    block.drain(..processed);
+                    if !after_add(self)? || self.is_done() {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+
+        if self.progress.sample_index == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Input file {:?} does not contain any samples",
+                    self.args.input
+                ),
+            ));
+        }
+
+        self.progress.pass += 1;
+        self.progress.sample_index = 0;
+        Ok(true)
+    }
+
+    /// Count the samples in `block` one by one, and add a token as soon as the thresholds are reached.
+    /// Returns the number of samples processed if a token was added, the rest have to be counted again.
+    fn process_block(&mut self, block: &[String]) -> Option<usize> {
+        let tokenized = tokenize_block(&self.aho, block, self.threads);
+
+        for (i, sample_tokens) in tokenized.iter().enumerate() {
+            self.count_sample(sample_tokens);
+
+            let progress = &self.progress;
+            if progress.top_count >= self.args.threshold_count
+                && progress.samples_since_add >= self.args.threshold_samples
+            {
+                self.add_token();
+                return Some(i + 1);
+            }
+        }
+
+        None
+    }
+
+    fn count_sample(&mut self, sample_tokens: &[usize]) {
+        let state = &mut self.state;
+        let progress = &mut self.progress;
+
+        progress.sample_index += 1;
+        progress.samples_since_add += 1;
+
+        let mut prev_token: Option<usize> = None;
+
+        for &curr_token in sample_tokens {
+            progress.tokens_since_add += 1;
+            state.unigram_count[curr_token] += 1;
 
             if let Some(prev_token) = prev_token {
                 // only combine tokens that are both or neither whitespace
-                if is_whitespace[prev_token] == is_whitespace[curr_token] {
-                    let count = &mut bigram_count[(prev_token, curr_token)];
-                    *count = count.saturating_add(1);
+                if state.is_whitespace[prev_token] == state.is_whitespace[curr_token] {
+                    let count = state.bigram_count.add(prev_token, curr_token, 1);
 
-                    if *count > top_count {
-                        top_count = *count;
-                        top_index = Some((prev_token, curr_token));
+                    if count > progress.top_count {
+                        progress.top_count = count;
+                        progress.top_index = Some((prev_token, curr_token));
                     }
                 }
             }
             prev_token = Some(curr_token);
         }
+    }
 
-        if top_count >= threshold_count && samples_since_add >= threshold_samples {
+    fn add_token(&mut self) {
+        let args = self.args;
+        let forced_token_count = self.forced_token_count;
+        let state = &mut self.state;
+        let progress = &mut self.progress;
+
+        let top_count = progress.top_count;
+        let (top_a, top_b) = progress.top_index.unwrap();
+
+        println!(
+            "Adding new token after {} samples, {} tokens, {} count, {} stored bigrams",
+            progress.samples_since_add,
+            progress.tokens_since_add,
+            top_count,
+            state.bigram_count.len(),
+        );
+
+        let now = Instant::now();
+        // add top token
+        {
+            assert_eq!(state.is_whitespace[top_a], state.is_whitespace[top_b]);
+
+            let new_token = [
+                state.tokens[top_a].as_slice(),
+                state.tokens[top_b].as_slice(),
+            ]
+            .concat();
             println!(
-                "Adding new token after {} samples, {} tokens, {} count",
-                samples_since_add, tokens_since_add, top_count
+                "  token {}: {:?} {:?} with count {} after {:?}",
+                state.tokens.len(),
+                String::from_utf8_lossy(&new_token),
+                new_token,
+                top_count,
+                now - self.prev_time,
             );
 
-            let now = Instant::now();
-            let (top_a, top_b) = top_index.unwrap();
+            state.tokens.push(new_token);
+            state.is_whitespace.push(state.is_whitespace[top_a]);
+            state.has_been_merged[top_a] |= true;
+            state.has_been_merged[top_b] |= true;
+            state.has_been_merged.push(false);
 
-            // add top token
-            {
-                assert_eq!(is_whitespace[top_a], is_whitespace[top_b]);
+            state.bigram_count.clear(top_a, top_b);
+            self.prev_time = now;
 
-                let new_token = [tokens[top_a].as_slice(), tokens[top_b].as_slice()].concat();
-                println!(
-                    "  token {}: {:?} {:?} with count {} after {:?}",
-                    tokens.len(),
-                    String::from_utf8_lossy(&new_token),
-                    new_token,
-                    top_count,
-                    now - prev_time,
-                );
+            // inherit bigram count
+            state.unigram_count.push(top_count);
+            state.unigram_count[top_a] -= top_count;
+            state.unigram_count[top_b] -= top_count;
 
-                tokens.push(new_token);
-                is_whitespace.push(is_whitespace[top_a]);
-                has_been_merged[top_a] |= true;
-                has_been_merged[top_b] |= true;
-                has_been_merged.push(false);
+            progress.tokens_added += 1;
+        }
 
-                bigram_count[(top_a, top_b)] = 0;
-                prev_time = now;
+        // TODO non-greedy tokenization (eg. try to capture as many bytes as possible per 3 tokens instead of only 1)
+        //   but then what about ping-pong stuff where the first token can constantly jump to a different short one?
+        //   only do non-greedy search per-"word"? kind of lame
+        // TODO immediately drop tokens with counts that reach zero
+        // TODO have some variance estimate for token counts, and only remove tokens below eg. 2*sigma
+        // TODO immediately add all tokens with counts > over threshold
+        //   overlapping ones will decay and be removed later anyway
+        // TODO decay per sample instead of per added token somehow?
+
+        // find least used token that has been merged, skip forced & last token
+        let least_used_token = state.unigram_count
+            [forced_token_count..state.unigram_count.len() - 1]
+            .iter()
+            .enumerate()
+            .map(|(i, &c)| (i + forced_token_count, c))
+            .filter(|&(i, _)| state.has_been_merged[i])
+            .min_by_key(|&(_, c)| c)
+            .map(|(i, _)| i);
+
+        if let Some(least_used_token) = least_used_token {
+            let least_used_count = state.unigram_count[least_used_token];
 
-                // inherit bigram count
-                unigram_count.push(top_count);
-                unigram_count[top_a] -= top_count;
-                unigram_count[top_b] -= top_count;
+            println!(
+                "Least used token: {:?} with count {} ({} drops)",
+                ByteString(&state.tokens[least_used_token]),
+                least_used_count,
+                progress.drops_applied,
+            );
+
+            // possibly remove least-used token
+            if least_used_count == 0
+                || (state.tokens.len() == args.max_tokens
+                    && progress.drops_applied < args.max_drops
+                    && ((least_used_count as f32 * args.threshold_drop_factor) as Count)
+                        < top_count)
+            {
+                // if it's the least used by a large enough margin and not the most recent token
+                progress.drops_applied += 1;
+                println!(
+                    "Removing token {:?} with only {} uses",
+                    ByteString(&state.tokens[least_used_token]),
+                    least_used_count
+                );
+
+                progress
+                    .dropped_tokens
+                    .push(state.remove_token(least_used_token));
             }
+        }
 
-            // TODO non-greedy tokenization (eg. try to capture as many bytes as possible per 3 tokens instead of only 1)
-            //   but then what about ping-pong stuff where the first token can constantly jump to a different short one?
-            //   only do non-greedy search per-"word"? kind of lame
-            // TODO immediately drop tokens with counts that reach zero
-            // TODO have some variance estimate for token counts, and only remove tokens below eg. 2*sigma
-            // TODO immediately add all tokens with counts > over threshold
-            //   overlapping ones will decay and be removed later anyway
-            // TODO decay per sample instead of per added token somehow?
-            //   maybe just decay on access (and store last modification time)
-
-            // find least used token that has been merged, skip forced & last token
-            let least_used_token = unigram_count[forced_token_count..unigram_count.len() - 1]
-                .iter()
-                .enumerate()
-                .map(|(i, &c)| (i + forced_token_count, c))
-                .filter(|&(i, _)| has_been_merged[i])
-                .min_by_key(|&(_, c)| c)
-                .map(|(i, _)| i);
-
-            if let Some(least_used_token) = least_used_token {
-                let least_used_count = unigram_count[least_used_token];
+        // periodically remove merged tokens that are no longer used on the held-out samples
+        progress.tokens_since_prune += 1;
+        if let Some(prune_interval) = args.prune_interval {
+            if progress.tokens_since_prune >= prune_interval {
+                progress.tokens_since_prune = 0;
 
+                let pruned = state.prune(&self.holdout, forced_token_count, args.prune_max_usage);
+                progress.pruned_slots += pruned.len();
                 println!(
-                    "Least used token: {:?} with count {} ({} drops)",
-                    ByteString(&tokens[least_used_token]),
-                    least_used_count,
-                    drops_applied,
+                    "Pruned {} obsolete tokens, {} slots reclaimed in total",
+                    pruned.len(),
+                    progress.pruned_slots
                 );
 
-                // possibly remove least-used token
-                if least_used_count == 0
-                    || (tokens.len() == max_tokens
-                        && drops_applied < max_drops
-                        && ((least_used_count as f32 * threshold_drop_factor) as Count) < top_count)
-                {
-                    // if it's the least used by a large enough margin and not the most recent token
-                    drops_applied += 1;
+                for (token, usage) in pruned {
                     println!(
-                        "Removing token {:?} with only {} uses",
-                        ByteString(&tokens[least_used_token]),
-                        least_used_count
+                        "  pruned {:?} with {} held-out uses",
+                        ByteString(&token),
+                        usage
                     );
+                    progress.dropped_tokens.push((token, usage as Count));
+                }
+            }
+        }
 
-                    let a = least_used_token;
-                    let b = tokens.len() - 1;
+        // invalidate state
+        progress.tokens_since_add = 0;
+        progress.samples_since_add = 0;
+        self.aho = build_tokenizer(&state.tokens);
 
-                    // remove by swapping everything
-                    dropped_tokens.push((tokens.swap_remove(a), least_used_count));
-                    unigram_count.swap_remove(a);
-                    has_been_merged.swap_remove(a);
+        progress.top_count = 0; // will immediately be set when incrementing again
+        progress.top_index = None;
 
-                    // 2D array, one swap operation per axis
-                    let (a_slice, b_slice) = bigram_count.multi_slice_mut((s![a, ..], s![b, ..]));
-                    Zip::from(a_slice).and(b_slice).for_each(std::mem::swap);
-                    let (a_slice, b_slice) = bigram_count.multi_slice_mut((s![.., a], s![.., b]));
-                    Zip::from(a_slice).and(b_slice).for_each(std::mem::swap);
-                }
-            }
+        // clip and decay counts to ensure old tokens go away over time
+        //   this is lazy for bigrams, they only actually decay when visited again
+        state.bigram_count.decay();
 
-            // invalidate state
-            tokens_since_add = 0;
-            samples_since_add = 0;
-            aho = build_tokenizer(&tokens);
-
-            top_count = 0; // will immediately be set when incrementing again
-            top_index = None;
-
-            // clip and decay counts to ensure old tokens go away over time
-            // TODO can we do this lazily while incrementing?
-            //    keep a last_seen index per bigram, when visiting decay as appropriate
-            bigram_count
-                .slice_mut(s![..tokens.len(), ..tokens.len()])
-                .mapv_inplace(|c| {
-                    let clipped = min(c, threshold_count);
-                    let scaled = clipped as u32 * count_decay_numerator / count_decay_denominator;
-                    scaled as Count
-                });
-
-            unigram_count.iter_mut().for_each(|c| {
-                *c = (*c as u32 * count_decay_numerator / count_decay_denominator) as Count
-            });
-        }
+        state
+            .unigram_count
+            .iter_mut()
+            .for_each(|c| *c = args.decay_unigram(*c));
+    }
+}
 
-        if tokens.len() >= max_tokens {
+fn main() -> std::io::Result<()> {
+    let args: Args = Args::parse();
+    println!("Args: {:#?}", args);
+
+    assert_eq!("zst", args.input.extension().unwrap());
+    assert_eq!("json", args.output.extension().unwrap());
+    std::fs::create_dir_all(args.output.parent().unwrap())?;
+
+    let threads = match args.threads {
+        Some(threads) => threads,
+        None => std::thread::available_parallelism()?.get(),
+    };
+
+    let pipeline = args.pipeline.build()?;
+
+    let resume = if args.resume {
+        let path = args.checkpoint.as_ref().unwrap();
+        println!("Resuming from checkpoint {:?}", path);
+        Some(load_checkpoint(path, &args)?)
+    } else {
+        None
+    };
+
+    let holdout = match args.prune_interval {
+        None => vec![],
+        Some(_) => {
+            // required by clap when pruning
+            let path = args.prune_input.as_ref().unwrap();
+            if *path == args.input {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "--prune-input must be held out, not the training input",
+                ));
+            }
+            SampleReader::open(path, pipeline.clone(), args.normalization)?
+                .take(args.prune_samples)
+                .map(|sample| sample.map(|sample| sample.text))
+                .collect::<std::io::Result<Vec<_>>>()?
+        }
+    };
+
+    let mut trainer = Trainer::new(&args, threads, holdout, resume);
+    while !trainer.is_done() {
+        let reader = SampleReader::open(&args.input, pipeline.clone(), args.normalization)?;
+        let samples = reader.map(|sample| sample.map(|sample| sample.text));
+
+        let finished_pass = trainer.run_pass(samples, |trainer| {
+            if let Some(checkpoint) = &args.checkpoint {
+                if trainer.progress.tokens_added % args.checkpoint_interval == 0 {
+                    println!("Writing checkpoint {:?}", checkpoint);
+                    save_checkpoint(checkpoint, &args, &trainer.state, &trainer.progress)?;
+                }
+            }
+            Ok(true)
+        })?;
+        if !finished_pass {
             break;
         }
     }
+    let Trainer {
+        state,
+        progress,
+        forced_token_count,
+        ..
+    } = trainer;
+
+    let State {
+        tokens,
+        unigram_count,
+        ..
+    } = state;
 
     if let Some(debug_path) = &args.debug_path {
         let mut debug_writer = BufWriter::new(File::create(debug_path)?);
@@ -278,17 +672,26 @@ fn main() -> std::io::Result<()> {
         }
 
         writeln!(&mut debug_writer, "\n\nDropped tokens:")?;
-        for (token, count) in dropped_tokens {
-            writeln!(&mut debug_writer, "  {:?}: {}", ByteString(&token), count)?;
+        for (token, count) in &progress.dropped_tokens {
+            writeln!(&mut debug_writer, "  {:?}: {}", ByteString(token), count)?;
         }
 
         debug_writer.flush()?;
         drop(debug_writer);
     }
 
+    if args.prune_interval.is_some() {
+        println!("Reclaimed {} token slots by pruning", progress.pruned_slots);
+    }
+
     println!("Writing output file");
     let mut vocab_writer = BufWriter::new(File::create(&args.output)?);
-    let output = Output { args, tokens };
+    let output = Output {
+        pipeline: pipeline.stages().to_vec(),
+        normalization: args.normalization,
+        args,
+        tokens,
+    };
     serde_json::to_writer(&mut vocab_writer, &output)?;
     vocab_writer.flush()?;
 
@@ -306,3 +709,223 @@ impl<'a> Debug for ByteString<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cmp::min;
+
+    use clap::Parser;
+    use itertools::Itertools;
+
+    use kt_core::batch::build_tokenizer;
+    use kt_core::bigram::Count;
+
+    use crate::{Args, Checkpoint, CheckpointRef, Trainer};
+
+    fn samples() -> Vec<String> {
+        let words = [
+            "the", "quick", "brown", "fox", "jumps", "over", "a", "lazy", "dog",
+        ];
+        (0..300)
+            .map(|i| {
+                (0..16)
+                    .map(|j| words[(i * 7 + j * j * 3) % words.len()])
+                    .join(" ")
+            })
+            .collect()
+    }
+
+    fn args() -> Args {
+        Args::parse_from([
+            "pick_tokens",
+            "input.jsonl.zst",
+            "output.json",
+            "--max-tokens",
+            "268",
+            "--threshold-count",
+            "20",
+            "--threshold-samples",
+            "4",
+            "--block-samples",
+            "8",
+        ])
+    }
+
+    /// Train until done, or until `stop_after` tokens have been added.
+    fn train(trainer: &mut Trainer, samples: &[String], stop_after: Option<usize>) {
+        while !trainer.is_done() {
+            let samples = samples.iter().cloned().map(Ok);
+            let finished_pass = trainer
+                .run_pass(samples, |trainer| {
+                    Ok(Some(trainer.progress.tokens_added) != stop_after)
+                })
+                .unwrap();
+            if !finished_pass {
+                break;
+            }
+        }
+    }
+
+    /// The straightforward sequential training loop with dense counts, without pruning.
+    fn train_sequential(args: &Args, samples: &[String]) -> Vec<Vec<u8>> {
+        let forced_token_count = u8::MAX as usize;
+        let mut tokens = (0..u8::MAX).map(|x| vec![x]).collect_vec();
+        let mut is_whitespace = (0..u8::MAX)
+            .map(|c| (c as char).is_whitespace())
+            .collect_vec();
+        let mut has_been_merged = vec![false; tokens.len()];
+        let mut unigram_count: Vec<Count> = vec![0; tokens.len()];
+        let mut bigram_count: Vec<Vec<Count>> = vec![vec![0; args.max_tokens]; args.max_tokens];
+
+        let mut aho = build_tokenizer(&tokens);
+        let mut samples_since_add = 0;
+        let mut top_count = 0;
+        let mut top_index = None;
+        let mut drops_applied = 0;
+
+        for sample in samples.iter().cycle() {
+            samples_since_add += 1;
+
+            let mut prev_token: Option<usize> = None;
+            for x in aho.find_iter(sample) {
+                let curr_token = x.pattern();
+                unigram_count[curr_token] += 1;
+
+                if let Some(prev_token) = prev_token {
+                    if is_whitespace[prev_token] == is_whitespace[curr_token] {
+                        let count = &mut bigram_count[prev_token][curr_token];
+                        *count = count.saturating_add(1);
+                        if *count > top_count {
+                            top_count = *count;
+                            top_index = Some((prev_token, curr_token));
+                        }
+                    }
+                }
+                prev_token = Some(curr_token);
+            }
+
+            if top_count >= args.threshold_count && samples_since_add >= args.threshold_samples {
+                let (top_a, top_b) = top_index.unwrap();
+                tokens.push([tokens[top_a].as_slice(), tokens[top_b].as_slice()].concat());
+                is_whitespace.push(is_whitespace[top_a]);
+                has_been_merged[top_a] = true;
+                has_been_merged[top_b] = true;
+                has_been_merged.push(false);
+                bigram_count[top_a][top_b] = 0;
+                unigram_count.push(top_count);
+                unigram_count[top_a] -= top_count;
+                unigram_count[top_b] -= top_count;
+
+                let least_used_token = (forced_token_count..tokens.len() - 1)
+                    .filter(|&i| has_been_merged[i])
+                    .min_by_key(|&i| unigram_count[i]);
+                if let Some(a) = least_used_token {
+                    let least_used_count = unigram_count[a];
+                    if least_used_count == 0
+                        || (tokens.len() == args.max_tokens
+                            && drops_applied < args.max_drops
+                            && ((least_used_count as f32 * args.threshold_drop_factor) as Count)
+                                < top_count)
+                    {
+                        drops_applied += 1;
+                        let b = tokens.len() - 1;
+                        tokens.swap_remove(a);
+                        is_whitespace.swap_remove(a);
+                        unigram_count.swap_remove(a);
+                        has_been_merged.swap_remove(a);
+
+                        bigram_count.swap(a, b);
+                        bigram_count[b].fill(0);
+                        for row in &mut bigram_count {
+                            row.swap(a, b);
+                            row[b] = 0;
+                        }
+                    }
+                }
+
+                samples_since_add = 0;
+                aho = build_tokenizer(&tokens);
+                top_count = 0;
+                top_index = None;
+
+                let decay = args.bigram_decay();
+                for row in &mut bigram_count {
+                    for c in row {
+                        *c = decay.apply(min(*c, args.threshold_count), 1);
+                    }
+                }
+                for c in &mut unigram_count {
+                    *c = args.decay_unigram(*c);
+                }
+            }
+
+            if tokens.len() >= args.max_tokens {
+                break;
+            }
+        }
+
+        tokens
+    }
+
+    #[test]
+    fn matches_sequential_loop() {
+        let args = args();
+        let samples = samples();
+        let expected = train_sequential(&args, &samples);
+
+        for threads in [1, 3] {
+            let mut trainer = Trainer::new(&args, threads, vec![], None);
+            train(&mut trainer, &samples, None);
+            assert_eq!(trainer.state.tokens, expected, "threads {}", threads);
+        }
+    }
+
+    #[test]
+    fn resume_matches_uninterrupted() {
+        let args = args();
+        let samples = samples();
+
+        let mut full = Trainer::new(&args, 2, vec![], None);
+        train(&mut full, &samples, None);
+        assert!(full.is_done());
+
+        let mut partial = Trainer::new(&args, 2, vec![], None);
+        train(&mut partial, &samples, Some(6));
+        assert!(!partial.is_done());
+        assert!(partial.progress.sample_index > 0);
+
+        let checkpoint = serde_json::to_string(&CheckpointRef {
+            args: &args,
+            state: &partial.state,
+            progress: &partial.progress,
+        })
+        .unwrap();
+        let checkpoint: Checkpoint = serde_json::from_str(&checkpoint).unwrap();
+
+        let resume = Some((checkpoint.state, checkpoint.progress));
+        let mut resumed = Trainer::new(&args, 2, vec![], resume);
+        train(&mut resumed, &samples, None);
+
+        assert_eq!(resumed.state.tokens, full.state.tokens);
+        assert_eq!(resumed.state.unigram_count, full.state.unigram_count);
+        assert_eq!(
+            resumed.progress.dropped_tokens,
+            full.progress.dropped_tokens
+        );
+    }
+
+    #[test]
+    fn threads_do_not_change_result() {
+        let args = args();
+        let samples = samples();
+
+        let mut single = Trainer::new(&args, 1, vec![], None);
+        train(&mut single, &samples, None);
+        let mut multi = Trainer::new(&args, 4, vec![], None);
+        train(&mut multi, &samples, None);
+
+        assert!(single.is_done());
+        assert_eq!(single.state.tokens, multi.state.tokens);
+        assert_eq!(single.state.unigram_count, multi.state.unigram_count);
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use kt_core::pipeline::PipelineArgs;
+use kt_core::prune::prune_vocab;
+use kt_core::sample::SampleReader;
+use kt_core::vocab::Vocab;
+
+#[derive(Debug, Parser)]
+struct Args {
+    vocab: PathBuf,
+    holdout: PathBuf,
+    output: PathBuf,
+
+    #[clap(long, default_value_t = 10_000)]
+    samples: usize,
+    /// Tokens used for at most this fraction of the held-out tokens are removed.
+    #[clap(long, default_value_t = 1e-6)]
+    max_usage: f64,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    assert_ne!(args.vocab, args.output);
+
+    let mut vocab = Vocab::load(&args.vocab)?;
+
+    let holdout = SampleReader::open(&args.holdout, args.pipeline.build()?, vocab.normalization)?
+        .take(args.samples)
+        .map(|sample| sample.map(|sample| sample.text))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let removed = prune_vocab(
+        &vocab.tokens,
+        holdout.iter().map(|s| s.as_str()),
+        args.max_usage,
+    );
+
+    for &(i, usage) in &removed {
+        println!(
+            "Removing token {:?} with {} held-out uses",
+            String::from_utf8_lossy(&vocab.tokens[i]),
+            usage
+        );
+    }
+
+    let before = vocab.tokens.len();
+    let mut index = 0;
+    vocab.tokens.retain(|_| {
+        let keep = removed.binary_search_by_key(&index, |&(i, _)| i).is_err();
+        index += 1;
+        keep
+    });
+
+    println!(
+        "Reclaimed {} of {} token slots using {} held-out samples",
+        removed.len(),
+        before,
+        holdout.len()
+    );
+
+    vocab.save(&args.output)?;
+    Ok(())
+}
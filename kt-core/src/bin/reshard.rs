@@ -0,0 +1,74 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use kt_core::normalize::Normalization;
+use kt_core::pipeline::PipelineArgs;
+use kt_core::sample::SampleReader;
+use kt_core::shard::{ShardConfig, ShardWriter};
+
+/// Run samples through the preprocessing pipeline and normalization,
+/// and write the result including metadata to new zstd JSONL shards.
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+    /// The directory to write the shards to.
+    #[clap(long)]
+    output: PathBuf,
+
+    #[clap(flatten)]
+    shard: ShardConfig,
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+    #[clap(long, default_value = "none")]
+    normalization: Normalization,
+
+    /// Maximum number of samples to write.
+    #[clap(long)]
+    max_samples: Option<u64>,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+
+    let mut pipeline = args.pipeline.build()?;
+    let mut writer = ShardWriter::new(&args.output, args.shard.clone())?;
+    let max_samples = args.max_samples.unwrap_or(u64::MAX);
+
+    for input in &args.inputs {
+        if writer.samples() >= max_samples {
+            break;
+        }
+        println!("Reading {:?}", input);
+        assert!(
+            !input.starts_with(&args.output),
+            "input {:?} is inside the output directory",
+            input
+        );
+
+        // the pipeline is carried over between inputs so dedup works across all of them
+        let mut reader = SampleReader::open(input, pipeline, args.normalization)?;
+        for sample in &mut reader {
+            writer.write(&sample?)?;
+            if writer.samples() >= max_samples {
+                break;
+            }
+        }
+        pipeline = reader.into_pipeline();
+    }
+
+    let samples = writer.samples();
+    let bytes = writer.bytes();
+    let paths = writer.finish()?;
+
+    println!(
+        "Wrote {} samples, {} bytes to {} shards",
+        samples,
+        bytes,
+        paths.len()
+    );
+    print!("Pipeline:\n{}", pipeline);
+
+    Ok(())
+}
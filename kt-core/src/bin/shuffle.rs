@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use kt_core::shard::{ShardConfig, ShardWriter};
+use kt_core::shuffle::{shuffle, ShuffleConfig};
+
+/// Globally shuffle the samples of all inputs into new zstd JSONL shards,
+/// keeping at most one temporary bucket in memory at a time.
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+    /// The directory to write the shards to.
+    #[clap(long)]
+    output: PathBuf,
+    /// Split the samples evenly over this many shards instead of using the shard size.
+    #[clap(long)]
+    shards: Option<usize>,
+    /// The directory for the temporary bucket files, defaults to a subdirectory of the output.
+    #[clap(long)]
+    temp_dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    shuffle: ShuffleConfig,
+    #[clap(flatten)]
+    shard: ShardConfig,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    for input in &args.inputs {
+        assert!(
+            !input.starts_with(&args.output),
+            "input {:?} is inside the output directory",
+            input
+        );
+    }
+
+    let mut shard = args.shard.clone();
+    if args.shards.is_some() {
+        shard.shard_size_mb = u64::MAX;
+    }
+
+    let temp_dir = args
+        .temp_dir
+        .clone()
+        .unwrap_or_else(|| args.output.join("shuffle-tmp"));
+    let mut writer = ShardWriter::new(&args.output, shard)?;
+
+    let stats = shuffle(
+        &args.inputs,
+        &temp_dir,
+        &mut writer,
+        args.shards,
+        &args.shuffle,
+    )?;
+    let paths = writer.finish()?;
+
+    println!(
+        "Shuffled {} samples, {} bytes into {} shards using {} buckets",
+        stats.samples,
+        stats.bytes,
+        paths.len(),
+        stats.buckets
+    );
+    let max_bucket_mb = stats.max_bucket_bytes / 1024 / 1024;
+    if max_bucket_mb > args.shuffle.memory_mb {
+        println!(
+            "Warning: the largest bucket was {} MB, more than the memory limit, consider increasing --buckets",
+            max_bucket_mb
+        );
+    }
+
+    Ok(())
+}
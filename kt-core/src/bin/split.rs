@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use clap::Parser;
+use zstd::Decoder;
+
+use kt_core::sample::Sample;
+use kt_core::shard::{ShardConfig, ShardWriter};
+use kt_core::split::{SplitConfig, Splitter};
+
+/// Split samples into train/validation/... sets based on a stable hash,
+/// writing the unchanged lines of each split to shards in a subdirectory of the output named after the split.
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+    /// The directory to write the splits to.
+    #[clap(long)]
+    output: PathBuf,
+
+    #[clap(flatten)]
+    split: SplitConfig,
+    #[clap(flatten)]
+    shard: ShardConfig,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+    let splitter = Splitter::new(&args.split)?;
+
+    let mut writers = splitter
+        .names()
+        .iter()
+        .map(|name| ShardWriter::new(args.output.join(name), args.shard.clone()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    // samples per split for each set
+    let mut per_set: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+
+    for input in &args.inputs {
+        println!("Reading {:?}", input);
+        assert!(
+            !input.starts_with(&args.output),
+            "input {:?} is inside the output directory",
+            input
+        );
+        let reader = BufReader::new(Decoder::new(File::open(input)?)?);
+
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let sample: Sample = serde_json::from_str(&line)?;
+
+            let split = splitter.split_of(&sample);
+            writers[split].write_line(line.as_bytes())?;
+
+            per_set
+                .entry(sample.meta.pile_set_name)
+                .or_insert_with(|| vec![0; writers.len()])[split] += 1;
+        }
+    }
+
+    for (name, writer) in splitter.names().iter().zip(writers) {
+        let samples = writer.samples();
+        let bytes = writer.bytes();
+        let shards = writer.finish()?.len();
+        println!(
+            "Split {}: {} samples, {} bytes, {} shards",
+            name, samples, bytes, shards
+        );
+    }
+    println!("Per set: samples for {:?}", splitter.names());
+    for (set, counts) in &per_set {
+        println!("  {}: {:?}", set, counts);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,273 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::Parser;
+use serde::Serialize;
+use unicode_script::{Script, UnicodeScript};
+
+use kt_core::clean::CharClass;
+use kt_core::normalize::Normalization;
+use kt_core::pipeline::Pipeline;
+use kt_core::sample::SampleReader;
+use kt_core::unicode::char_is_ltr;
+
+/// Stream through corpus shards and report statistics about the raw samples.
+#[derive(Debug, Parser)]
+struct Args {
+    #[clap(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// Maximum number of samples to read from each input.
+    #[clap(long)]
+    max_samples: Option<usize>,
+    /// Count how many samples are changed by this normalization.
+    #[clap(long, default_value = "nfc")]
+    normalization: Normalization,
+    /// Also write the full report as JSON to this path.
+    #[clap(long)]
+    json: Option<PathBuf>,
+    /// Print the first line of this many samples that contain RTL chars or are changed by normalization.
+    #[clap(long, default_value_t = 0)]
+    examples: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    samples: u64,
+    bytes: u64,
+    chars: u64,
+    bytes_per_sample: Distribution,
+    chars_per_sample: Distribution,
+    per_set: BTreeMap<String, SetTotals>,
+
+    normalization: String,
+    normalization_changed_samples: u64,
+
+    rtl_samples: u64,
+    rtl_chars: u64,
+    /// The number of chars and samples for each class of invalid or invisible chars.
+    invalid: BTreeMap<&'static str, InvalidCounts>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct SetTotals {
+    samples: u64,
+    bytes: u64,
+    chars: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct InvalidCounts {
+    chars: u64,
+    samples: u64,
+}
+
+/// Summary of a distribution of non-negative integers, with a histogram of power-of-two buckets.
+#[derive(Debug, Default, Serialize)]
+struct Distribution {
+    count: u64,
+    sum: u64,
+    min: Option<u64>,
+    max: Option<u64>,
+    /// Bucket `i` counts the values in `2^(i-1)..2^i`, bucket 0 counts the zeros.
+    histogram: Vec<u64>,
+}
+
+fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+
+    let mut report = Report {
+        normalization: args.normalization.to_string(),
+        ..Report::default()
+    };
+    let mut examples = 0;
+
+    for input in &args.inputs {
+        println!("Reading {:?}", input);
+        let reader = SampleReader::open(input, Pipeline::empty(), Normalization::NONE)?;
+
+        for sample in reader.take(args.max_samples.unwrap_or(usize::MAX)) {
+            let sample = sample?;
+            let text = &sample.text;
+
+            let mut chars = 0;
+            let mut rtl_chars = 0;
+            let mut invalid: BTreeMap<&'static str, u64> = BTreeMap::new();
+            for c in text.chars() {
+                chars += 1;
+                if !char_is_ltr(c) {
+                    rtl_chars += 1;
+                }
+                if let Some(class) = invalid_class(c) {
+                    *invalid.entry(class).or_default() += 1;
+                }
+            }
+
+            let bytes = text.len() as u64;
+            report.samples += 1;
+            report.bytes += bytes;
+            report.chars += chars;
+            report.bytes_per_sample.add(bytes);
+            report.chars_per_sample.add(chars);
+
+            let set = report.per_set.entry(sample.meta.pile_set_name).or_default();
+            set.samples += 1;
+            set.bytes += bytes;
+            set.chars += chars;
+
+            report.rtl_chars += rtl_chars;
+            if rtl_chars > 0 {
+                report.rtl_samples += 1;
+            }
+            for (class, count) in invalid {
+                let counts = report.invalid.entry(class).or_default();
+                counts.chars += count;
+                counts.samples += 1;
+            }
+
+            let normalized = args.normalization.apply(text);
+            let normalization_changed = normalized != *text;
+            if normalization_changed {
+                report.normalization_changed_samples += 1;
+            }
+
+            if examples < args.examples && (rtl_chars > 0 || normalization_changed) {
+                examples += 1;
+                let line = text
+                    .lines()
+                    .zip(normalized.lines())
+                    .find(|(a, b)| a != b || a.chars().any(|c| !char_is_ltr(c)))
+                    .map_or("", |(a, _)| a);
+                println!(
+                    "  sample {}: rtl chars {}, normalization changed {}, line {:?}",
+                    report.samples - 1,
+                    rtl_chars,
+                    normalization_changed,
+                    line
+                );
+            }
+
+            if report.samples.is_multiple_of(100_000) {
+                println!("  {} samples, {} bytes", report.samples, report.bytes);
+            }
+        }
+    }
+
+    print_report(&report);
+
+    if let Some(path) = &args.json {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &report)?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// The class of invalid or invisible char `c` belongs to, if any.
+fn invalid_class(c: char) -> Option<&'static str> {
+    if c.is_ascii() && !c.is_ascii_control() {
+        return None;
+    }
+    if let Some(class) = CharClass::of(c) {
+        return Some(class.name());
+    }
+
+    let u = c as u32;
+    if c == '\u{fffd}' {
+        Some("replacement")
+    } else if (0xfdd0..=0xfdef).contains(&u) || (u & 0xfffe) == 0xfffe {
+        Some("noncharacter")
+    } else if (0xe000..=0xf8ff).contains(&u) || u >= 0xf0000 {
+        Some("private-use")
+    } else if c.script() == Script::Unknown {
+        Some("unassigned")
+    } else {
+        None
+    }
+}
+
+impl Distribution {
+    fn add(&mut self, value: u64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = Some(self.min.map_or(value, |min| min.min(value)));
+        self.max = Some(self.max.map_or(value, |max| max.max(value)));
+
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        if self.histogram.len() <= bucket {
+            self.histogram.resize(bucket + 1, 0);
+        }
+        self.histogram[bucket] += 1;
+    }
+
+    fn mean(&self) -> f64 {
+        self.sum as f64 / self.count.max(1) as f64
+    }
+
+    /// An upper bound for the given quantile, based on the histogram buckets.
+    fn quantile_bound(&self, q: f64) -> u64 {
+        let target = (q * self.count as f64).ceil() as u64;
+        let mut total = 0;
+        for (i, &count) in self.histogram.iter().enumerate() {
+            total += count;
+            if total >= target {
+                let bound = if i == 0 { 0 } else { (1 << i) - 1 };
+                return bound.min(self.max.unwrap_or(0));
+            }
+        }
+        self.max.unwrap_or(0)
+    }
+}
+
+fn print_report(report: &Report) {
+    println!(
+        "Samples {}, bytes {}, chars {}",
+        report.samples, report.bytes, report.chars
+    );
+    print_distribution("Bytes per sample", &report.bytes_per_sample);
+    print_distribution("Chars per sample", &report.chars_per_sample);
+
+    println!("Per set: (samples, bytes, chars)");
+    for (name, set) in &report.per_set {
+        println!("  {}: {}, {}, {}", name, set.samples, set.bytes, set.chars);
+    }
+
+    println!(
+        "Changed by {} normalization: {} samples ({:.2}%)",
+        report.normalization,
+        report.normalization_changed_samples,
+        percent(report.normalization_changed_samples, report.samples)
+    );
+    println!(
+        "RTL: {} samples ({:.2}%), {} chars ({:.4}%)",
+        report.rtl_samples,
+        percent(report.rtl_samples, report.samples),
+        report.rtl_chars,
+        percent(report.rtl_chars, report.chars)
+    );
+
+    println!("Invalid chars: (chars, samples)");
+    for (class, counts) in &report.invalid {
+        println!("  {}: {}, {}", class, counts.chars, counts.samples);
+    }
+}
+
+fn print_distribution(name: &str, dist: &Distribution) {
+    println!(
+        "{}: min {}, mean {:.1}, max {}, p50 <= {}, p90 <= {}, p99 <= {}",
+        name,
+        dist.min.unwrap_or(0),
+        dist.mean(),
+        dist.max.unwrap_or(0),
+        dist.quantile_bound(0.5),
+        dist.quantile_bound(0.9),
+        dist.quantile_bound(0.99)
+    );
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    count as f64 / total.max(1) as f64 * 100.0
+}
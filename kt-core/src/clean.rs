@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+use unicode_bidi::{bidi_class, BidiClass};
+
+use crate::pipeline::StageCounts;
+
+/// NEL, the C1 control char for a line break.
+const NEXT_LINE: char = '\u{85}';
+
+/// Classes of invisible or control chars that can be removed from samples.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CharClass {
+    /// Explicit bidi formatting chars (LRE, RLO, PDI, ...) and the implicit marks LRM, RLM and ALM.
+    BidiControl,
+    /// Zero-width spaces and joiners, the word joiner, invisible math operators and the soft hyphen.
+    ZeroWidth,
+    /// The byte order mark, also known as the zero-width no-break space.
+    Bom,
+    /// ASCII control chars except tab, newline and carriage return.
+    C0Control,
+    /// The C1 control chars `U+0080..=U+009F`.
+    /// The next line char NEL (`U+0085`) is a line break, it is replaced by `\n` instead of removed.
+    C1Control,
+}
+
+impl CharClass {
+    pub const ALL: [CharClass; 5] = [
+        CharClass::BidiControl,
+        CharClass::ZeroWidth,
+        CharClass::Bom,
+        CharClass::C0Control,
+        CharClass::C1Control,
+    ];
+
+    pub fn of(c: char) -> Option<CharClass> {
+        // fast path for printable ASCII and common whitespace
+        if (' '..'\x7f').contains(&c) || matches!(c, '\t' | '\n' | '\r') {
+            return None;
+        }
+
+        match c {
+            '\0'..='\x1f' | '\x7f' => Some(CharClass::C0Control),
+            '\u{80}'..='\u{9f}' => Some(CharClass::C1Control),
+            '\u{feff}' => Some(CharClass::Bom),
+            '\u{200b}' | '\u{200c}' | '\u{200d}' | '\u{2060}' | '\u{2061}'..='\u{2064}' => {
+                Some(CharClass::ZeroWidth)
+            }
+            '\u{ad}' | '\u{180e}' => Some(CharClass::ZeroWidth),
+            '\u{200e}' | '\u{200f}' | '\u{61c}' => Some(CharClass::BidiControl),
+            _ => match bidi_class(c) {
+                BidiClass::LRE
+                | BidiClass::LRO
+                | BidiClass::RLE
+                | BidiClass::RLO
+                | BidiClass::PDF
+                | BidiClass::LRI
+                | BidiClass::RLI
+                | BidiClass::FSI
+                | BidiClass::PDI => Some(CharClass::BidiControl),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CharClass::BidiControl => "bidi-control",
+            CharClass::ZeroWidth => "zero-width",
+            CharClass::Bom => "bom",
+            CharClass::C0Control => "c0-control",
+            CharClass::C1Control => "c1-control",
+        }
+    }
+}
+
+/// Removes (or replaces) invisible and control chars from samples.
+///
+/// This runs before the [ScriptFilter](crate::script::ScriptFilter),
+/// so a stray bidi control char no longer causes the entire sample to be dropped.
+#[derive(Debug, Clone, Default, PartialEq, clap::Args, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TextCleaner {
+    /// Classes of chars to remove from samples, eg. "bidi-control,zero-width,bom".
+    #[clap(long = "clean", value_enum, value_delimiter = ',')]
+    pub classes: Vec<CharClass>,
+    /// Replace removed chars by this char instead of deleting them.
+    #[clap(long = "clean-replacement")]
+    pub replacement: Option<char>,
+}
+
+impl TextCleaner {
+    pub fn all() -> Self {
+        TextCleaner {
+            classes: CharClass::ALL.to_vec(),
+            replacement: None,
+        }
+    }
+
+    fn removes(&self, c: char) -> Option<CharClass> {
+        CharClass::of(c).filter(|class| self.classes.contains(class))
+    }
+
+    /// Clean `text` in-place, adding the number of removed chars per class to `counts`.
+    pub fn apply(&self, text: &mut String, counts: &mut StageCounts) {
+        if self.classes.is_empty() {
+            return;
+        }
+
+        // most samples don't need any changes, avoid allocating a new string for them
+        let first = match text
+            .char_indices()
+            .find(|&(_, c)| self.removes(c).is_some())
+        {
+            None => return,
+            Some((first, _)) => first,
+        };
+
+        let mut result = String::with_capacity(text.len());
+        result.push_str(&text[..first]);
+
+        for c in text[first..].chars() {
+            match self.removes(c) {
+                None => result.push(c),
+                Some(class) => {
+                    counts.add_detail(class.name(), 1);
+                    if c == NEXT_LINE {
+                        result.push('\n');
+                    } else if let Some(replacement) = self.replacement {
+                        result.push(replacement);
+                    }
+                }
+            }
+        }
+
+        counts.changed += 1;
+        *text = result;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clean::{CharClass, TextCleaner};
+    use crate::pipeline::StageCounts;
+    use crate::script::ScriptFilter;
+
+    #[test]
+    fn classes() {
+        assert_eq!(CharClass::of('a'), None);
+        assert_eq!(CharClass::of('\n'), None);
+        assert_eq!(CharClass::of('\u{e9}'), None);
+        assert_eq!(CharClass::of('\0'), Some(CharClass::C0Control));
+        assert_eq!(CharClass::of('\u{85}'), Some(CharClass::C1Control));
+        assert_eq!(CharClass::of('\u{feff}'), Some(CharClass::Bom));
+        assert_eq!(CharClass::of('\u{200b}'), Some(CharClass::ZeroWidth));
+        assert_eq!(CharClass::of('\u{202e}'), Some(CharClass::BidiControl));
+        assert_eq!(CharClass::of('\u{2069}'), Some(CharClass::BidiControl));
+        assert_eq!(CharClass::of('\u{200f}'), Some(CharClass::BidiControl));
+    }
+
+    #[test]
+    fn clean_then_ltr() {
+        let original = "\u{feff}some \u{202a}quoted\u{202c} text\u{200b}\x07";
+
+        let mut text = original.to_owned();
+        let mut counts = StageCounts::default();
+        TextCleaner::all().apply(&mut text, &mut counts);
+
+        assert_eq!(text, "some quoted text");
+        assert_eq!(counts.changed, 1);
+        assert_eq!(counts.details["bidi-control"], 2);
+        assert_eq!(counts.details["bom"], 1);
+        assert_eq!(counts.details["zero-width"], 1);
+        assert_eq!(counts.details["c0-control"], 1);
+        assert!(!counts.details.contains_key("c1-control"));
+
+        // the cleaned text passes the LTR check, the original doesn't
+        assert!(ScriptFilter::ltr_only().apply(text).is_some());
+        assert!(ScriptFilter::ltr_only()
+            .apply(original.to_owned())
+            .is_none());
+    }
+
+    #[test]
+    fn next_line_is_newline() {
+        let cleaner = TextCleaner {
+            replacement: Some(' '),
+            ..TextCleaner::all()
+        };
+        let mut text = "first\u{85}second\u{9f}line".to_owned();
+        let mut counts = StageCounts::default();
+        cleaner.apply(&mut text, &mut counts);
+
+        assert_eq!(text, "first\nsecond line");
+        assert_eq!(counts.details["c1-control"], 2);
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use smallvec::SmallVec;
+
+use crate::error::invalid_input;
+use crate::hash::{combine, stable_hash};
+use crate::pipeline::StageCounts;
+
+/// Settings for removing samples that overlap with evaluation sets.
+#[derive(Debug, Clone, PartialEq, clap::Args, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecontamConfig {
+    /// Eval set files, either plain text or JSONL where every string value is a separate document.
+    /// Each file is reported as a separate source.
+    #[clap(long = "eval", required = true)]
+    pub eval: Vec<PathBuf>,
+    /// The number of normalized words in each n-gram. Eval documents with fewer words are ignored.
+    #[clap(long, default_value_t = 13)]
+    pub ngram: usize,
+    /// The number of distinct overlapping n-grams a sample needs to be considered contaminated.
+    #[clap(long, default_value_t = 1)]
+    pub min_hits: usize,
+    /// Only count contaminated samples instead of dropping them.
+    #[clap(long)]
+    pub report_only: bool,
+}
+
+/// The set of n-grams that occur in the eval sets.
+#[derive(Debug)]
+pub struct NgramIndex {
+    n: usize,
+    sources: Vec<String>,
+    // n-gram hash to the sources it occurs in, in increasing order
+    ngrams: HashMap<u64, SmallVec<[u32; 2]>>,
+}
+
+/// The overlap between a sample and the eval sets.
+#[derive(Debug, Clone, Default)]
+pub struct Contamination {
+    pub hits: usize,
+    /// The number of hits for each source that has any, as `(source index, hits)`.
+    pub source_hits: Vec<(usize, usize)>,
+    /// The byte range of the first overlapping n-gram in the sample.
+    pub first: Option<Range<usize>>,
+}
+
+impl Default for DecontamConfig {
+    fn default() -> Self {
+        DecontamConfig {
+            eval: vec![],
+            ngram: 13,
+            min_hits: 1,
+            report_only: false,
+        }
+    }
+}
+
+impl NgramIndex {
+    pub fn new(n: usize) -> std::io::Result<Self> {
+        if n == 0 {
+            return Err(invalid_input("The n-gram size must be positive".to_owned()));
+        }
+        Ok(NgramIndex {
+            n,
+            sources: vec![],
+            ngrams: HashMap::new(),
+        })
+    }
+
+    pub fn load(config: &DecontamConfig) -> std::io::Result<Self> {
+        let mut index = NgramIndex::new(config.ngram)?;
+        for path in &config.eval {
+            index.add_file(path)?;
+        }
+        Ok(index)
+    }
+
+    pub fn sources(&self) -> &[String] {
+        &self.sources
+    }
+
+    pub fn len(&self) -> usize {
+        self.ngrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ngrams.is_empty()
+    }
+
+    pub fn add_source<'a>(&mut self, name: String, docs: impl IntoIterator<Item = &'a str>) {
+        let source = self.sources.len() as u32;
+        self.sources.push(name);
+
+        for doc in docs {
+            for (hash, _) in ngram_hashes(doc, self.n) {
+                let sources = self.ngrams.entry(hash).or_default();
+                if sources.last() != Some(&source) {
+                    sources.push(source);
+                }
+            }
+        }
+    }
+
+    /// Add a plain text or JSONL file as a new source, named after the file.
+    pub fn add_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let name = path.file_name().map_or_else(
+            || path.display().to_string(),
+            |n| n.to_string_lossy().into_owned(),
+        );
+
+        let docs = if path.extension().is_some_and(|e| e == "jsonl") {
+            let mut docs = vec![];
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    collect_strings(serde_json::from_str(&line)?, &mut docs);
+                }
+            }
+            docs
+        } else {
+            let mut text = String::new();
+            File::open(path)?.read_to_string(&mut text)?;
+            vec![text]
+        };
+
+        self.add_source(name, docs.iter().map(|s| s.as_str()));
+        Ok(())
+    }
+
+    pub fn check(&self, text: &str) -> Contamination {
+        let mut result = Contamination::default();
+        if self.ngrams.is_empty() {
+            return result;
+        }
+
+        let mut seen = HashSet::new();
+        let mut source_hits = vec![0; self.sources.len()];
+        for (hash, range) in ngram_hashes(text, self.n) {
+            if let Some(sources) = self.ngrams.get(&hash) {
+                if seen.insert(hash) {
+                    result.hits += 1;
+                    result.first.get_or_insert(range);
+                    for &source in sources {
+                        source_hits[source as usize] += 1;
+                    }
+                }
+            }
+        }
+
+        result.source_hits = source_hits
+            .into_iter()
+            .enumerate()
+            .filter(|&(_, hits)| hits > 0)
+            .collect();
+
+        result
+    }
+
+    /// Drop (or just count) contaminated samples, for use as a pipeline stage.
+    pub fn apply(
+        &self,
+        config: &DecontamConfig,
+        text: String,
+        counts: &mut StageCounts,
+    ) -> Option<String> {
+        let contamination = self.check(&text);
+        if contamination.hits < config.min_hits.max(1) {
+            return Some(text);
+        }
+
+        counts.add_detail("contaminated", 1);
+        for &(source, _) in &contamination.source_hits {
+            counts.add_detail(&self.sources[source], 1);
+        }
+
+        if config.report_only {
+            Some(text)
+        } else {
+            None
+        }
+    }
+}
+
+// split `text` into lowercase words of alphanumeric chars, yielding the hash and byte range of each word.
+fn word_hashes(text: &str) -> impl Iterator<Item = (u64, Range<usize>)> + '_ {
+    let mut buffer = String::new();
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(move |w| {
+            buffer.clear();
+            buffer.extend(w.chars().flat_map(char::to_lowercase));
+            let start = w.as_ptr() as usize - text.as_ptr() as usize;
+            (stable_hash(buffer.as_bytes()), start..start + w.len())
+        })
+}
+
+// the hash and byte range of each n-gram of normalized words in `text`.
+fn ngram_hashes(text: &str, n: usize) -> Vec<(u64, Range<usize>)> {
+    let words = word_hashes(text).collect::<Vec<_>>();
+    words
+        .windows(n)
+        .map(|w| {
+            let hash = w.iter().fold(0, |acc, (h, _)| combine(acc, *h));
+            (hash, w[0].1.start..w[n - 1].1.end)
+        })
+        .collect()
+}
+
+fn collect_strings(value: Value, docs: &mut Vec<String>) {
+    match value {
+        Value::String(s) => docs.push(s),
+        Value::Array(values) => values.into_iter().for_each(|v| collect_strings(v, docs)),
+        Value::Object(map) => map.into_iter().for_each(|(_, v)| collect_strings(v, docs)),
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::decontam::NgramIndex;
+
+    #[test]
+    fn overlap() {
+        let mut index = NgramIndex::new(4).unwrap();
+        index.add_source(
+            "quiz".to_owned(),
+            ["What is the capital of France? Paris.", "too short"],
+        );
+        index.add_source("other".to_owned(), ["one two three four five"]);
+        assert_eq!(index.len(), 4 + 2);
+
+        let clean = index.check("Paris is the capital city of France.");
+        assert_eq!(clean.hits, 0);
+
+        let text = "Q: what IS the capital of\nfrance?? A: paris";
+        let dirty = index.check(text);
+        assert_eq!(dirty.hits, 3);
+        assert_eq!(dirty.source_hits, vec![(0, 3)]);
+        assert_eq!(&text[dirty.first.unwrap()], "what IS the capital");
+    }
+
+    #[test]
+    fn shared_ngrams() {
+        let mut index = NgramIndex::new(3).unwrap();
+        index.add_source("a".to_owned(), ["one two three four", "one two three"]);
+        index.add_source("b".to_owned(), ["two three four five"]);
+        index.add_source("c".to_owned(), ["six seven eight"]);
+        assert_eq!(index.len(), 4);
+
+        // "two three four" is in both sources, "one two three" only in the first
+        let contamination = index.check("one two three four");
+        assert_eq!(contamination.hits, 2);
+        assert_eq!(contamination.source_hits, vec![(0, 2), (1, 1)]);
+
+        let contamination = index.check("two three four five");
+        assert_eq!(contamination.hits, 2);
+        assert_eq!(contamination.source_hits, vec![(0, 1), (1, 2)]);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::HashSet;
+
+use clap::ArgAction;
+use serde::{Deserialize, Serialize};
+
+use crate::error::invalid_input;
+use crate::hash::{combine, mix64, stable_hash};
+use crate::pipeline::StageCounts;
+
+/// Settings for exact and near-duplicate detection.
+///
+/// Near-duplicates are found with MinHash over shingles and LSH with `bands` bands of `rows` hashes each,
+/// samples with a shingle Jaccard similarity above roughly `(1 / bands) ^ (1 / rows)` are considered duplicates.
+#[derive(Debug, Clone, PartialEq, clap::Args, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DedupConfig {
+    /// Drop samples with exactly the same text as an earlier sample.
+    #[clap(long, default_value_t = true, action = ArgAction::Set)]
+    pub exact: bool,
+    /// Drop samples that are very similar to an earlier sample.
+    #[clap(long, default_value_t = true, action = ArgAction::Set)]
+    pub near: bool,
+
+    #[clap(long, value_enum, default_value_t = ShingleKind::Words)]
+    pub shingle: ShingleKind,
+    /// The number of words or chars in each shingle.
+    #[clap(long, default_value_t = 5)]
+    pub shingle_size: usize,
+    #[clap(long, default_value_t = 16)]
+    pub bands: usize,
+    #[clap(long, default_value_t = 8)]
+    pub rows: usize,
+
+    /// Approximate memory limit for the remembered hashes. Once it is reached the oldest half is forgotten,
+    /// so duplicates that are very far apart can be missed.
+    #[clap(long, default_value_t = 1024)]
+    pub memory_mb: usize,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ShingleKind {
+    Words,
+    Chars,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Duplicate {
+    Exact,
+    Near,
+}
+
+/// Remembers the hashes of the samples seen so far to detect duplicates.
+#[derive(Debug, Clone)]
+pub struct Deduplicator {
+    config: DedupConfig,
+    seeds: Vec<u64>,
+
+    // two generations of hashes, when the current one is full the previous one is dropped
+    current: Generation,
+    previous: Generation,
+}
+
+#[derive(Debug, Clone)]
+struct Generation {
+    exact: HashSet<u64>,
+    bands: Vec<HashSet<u64>>,
+    entries: usize,
+}
+
+// rough estimate of the memory used by a single hash set entry, including the load factor overhead
+const BYTES_PER_ENTRY: usize = 16;
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            exact: true,
+            near: true,
+            shingle: ShingleKind::Words,
+            shingle_size: 5,
+            bands: 16,
+            rows: 8,
+            memory_mb: 1024,
+        }
+    }
+}
+
+impl Deduplicator {
+    pub fn new(config: DedupConfig) -> std::io::Result<Self> {
+        if config.shingle_size == 0 || config.bands == 0 || config.rows == 0 {
+            return Err(invalid_input(format!(
+                "Dedup shingle_size, bands and rows must be positive, got {:?}",
+                config
+            )));
+        }
+
+        let seeds = (0..config.bands * config.rows)
+            .map(|i| mix64(i as u64 + 1))
+            .collect();
+        Ok(Deduplicator {
+            seeds,
+            current: Generation::new(config.bands),
+            previous: Generation::new(config.bands),
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &DedupConfig {
+        &self.config
+    }
+
+    /// Check whether `text` is a duplicate of an earlier sample, if not remember it.
+    pub fn check(&mut self, text: &str) -> Option<Duplicate> {
+        let exact_hash = stable_hash(text.as_bytes());
+        if self.config.exact && self.contains(|g| g.exact.contains(&exact_hash)) {
+            return Some(Duplicate::Exact);
+        }
+
+        let band_hashes = if self.config.near {
+            let band_hashes = self.band_hashes(text);
+            let is_near = self.contains(|g| {
+                band_hashes
+                    .iter()
+                    .zip(&g.bands)
+                    .any(|(h, band)| band.contains(h))
+            });
+            if is_near {
+                return Some(Duplicate::Near);
+            }
+            band_hashes
+        } else {
+            vec![]
+        };
+
+        self.insert(exact_hash, band_hashes);
+        None
+    }
+
+    pub fn clear(&mut self) {
+        self.current = Generation::new(self.config.bands);
+        self.previous = Generation::new(self.config.bands);
+    }
+
+    /// Estimate of the memory used by the remembered hashes in bytes.
+    pub fn memory_usage(&self) -> usize {
+        (self.current.entries + self.previous.entries) * BYTES_PER_ENTRY
+    }
+
+    pub fn apply(&mut self, text: String, counts: &mut StageCounts) -> Option<String> {
+        match self.check(&text) {
+            None => Some(text),
+            Some(Duplicate::Exact) => {
+                counts.add_detail("exact", 1);
+                None
+            }
+            Some(Duplicate::Near) => {
+                counts.add_detail("near", 1);
+                None
+            }
+        }
+    }
+
+    fn contains(&self, f: impl Fn(&Generation) -> bool) -> bool {
+        f(&self.current) || f(&self.previous)
+    }
+
+    fn insert(&mut self, exact_hash: u64, band_hashes: Vec<u64>) {
+        // each generation gets half of the budget
+        let max_entries = self.config.memory_mb * 1024 * 1024 / BYTES_PER_ENTRY / 2;
+        let new_entries = self.config.exact as usize + band_hashes.len();
+        if self.current.entries + new_entries > max_entries {
+            self.previous =
+                std::mem::replace(&mut self.current, Generation::new(self.config.bands));
+        }
+
+        let generation = &mut self.current;
+        if self.config.exact {
+            generation.exact.insert(exact_hash);
+        }
+        for (band, hash) in generation.bands.iter_mut().zip(band_hashes) {
+            band.insert(hash);
+        }
+        generation.entries += new_entries;
+    }
+
+    // the MinHash signature of `text`, with each band of rows combined into a single hash.
+    fn band_hashes(&self, text: &str) -> Vec<u64> {
+        let shingles = shingle_hashes(text, self.config.shingle, self.config.shingle_size);
+        if shingles.is_empty() {
+            // treat empty samples as a single empty shingle, so they are still near-duplicates of each other
+            return self.band_hashes_for(&[0]);
+        }
+        self.band_hashes_for(&shingles)
+    }
+
+    fn band_hashes_for(&self, shingles: &[u64]) -> Vec<u64> {
+        let signature = self
+            .seeds
+            .iter()
+            .map(|&seed| shingles.iter().map(|&s| mix64(s ^ seed)).min().unwrap())
+            .collect::<Vec<_>>();
+
+        signature
+            .chunks(self.config.rows)
+            .map(|rows| rows.iter().fold(0, |acc, &h| combine(acc, h)))
+            .collect()
+    }
+}
+
+impl Generation {
+    fn new(bands: usize) -> Self {
+        Generation {
+            exact: HashSet::new(),
+            bands: vec![HashSet::new(); bands],
+            entries: 0,
+        }
+    }
+}
+
+// the hashes of all overlapping shingles in `text`, texts shorter than a single shingle are one shingle.
+fn shingle_hashes(text: &str, kind: ShingleKind, size: usize) -> Vec<u64> {
+    match kind {
+        ShingleKind::Words => {
+            let words = text
+                .split_whitespace()
+                .map(|w| stable_hash(w.as_bytes()))
+                .collect::<Vec<_>>();
+            windows_or_all(&words, size)
+                .map(|w| w.iter().fold(0, |acc, &h| combine(acc, h)))
+                .collect()
+        }
+        ShingleKind::Chars => {
+            let starts = text
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(text.len()))
+                .collect::<Vec<_>>();
+            windows_or_all(&starts, size + 1)
+                .map(|w| stable_hash(&text.as_bytes()[w[0]..w[w.len() - 1]]))
+                .collect()
+        }
+    }
+}
+
+fn windows_or_all<T>(items: &[T], size: usize) -> impl Iterator<Item = &[T]> {
+    let all = (items.len() < size && !items.is_empty()).then_some(items);
+    items.windows(size).chain(all)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dedup::{DedupConfig, Deduplicator, Duplicate, ShingleKind};
+
+    fn text(i: usize, changed: usize) -> String {
+        (0..200)
+            .map(|j| {
+                if j < changed {
+                    format!("changed{}", j)
+                } else {
+                    format!("word{}", (i * 1000 + j))
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn exact_and_near() {
+        for shingle in [ShingleKind::Words, ShingleKind::Chars] {
+            let mut dedup = Deduplicator::new(DedupConfig {
+                shingle,
+                ..DedupConfig::default()
+            })
+            .unwrap();
+
+            assert_eq!(dedup.check(&text(0, 0)), None);
+            assert_eq!(dedup.check(&text(1, 0)), None);
+            assert_eq!(dedup.check(&text(0, 0)), Some(Duplicate::Exact));
+            assert_eq!(dedup.check(&text(1, 2)), Some(Duplicate::Near));
+            assert_eq!(dedup.check(&text(2, 0)), None);
+        }
+    }
+
+    #[test]
+    fn memory_budget() {
+        let mut dedup = Deduplicator::new(DedupConfig {
+            near: false,
+            memory_mb: 1,
+            ..DedupConfig::default()
+        })
+        .unwrap();
+
+        let generation_entries = 1024 * 1024 / 16 / 2;
+        let n = 3 * generation_entries;
+        for i in 0..n {
+            assert_eq!(dedup.check(&i.to_string()), None);
+        }
+        assert!(dedup.memory_usage() <= 1024 * 1024);
+
+        // recent samples are still remembered, the oldest ones have been forgotten
+        assert_eq!(dedup.check(&(n - 1).to_string()), Some(Duplicate::Exact));
+        assert_eq!(dedup.check("0"), None);
+    }
+}
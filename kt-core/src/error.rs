@@ -0,0 +1,167 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// An error while reading samples, with the location where it happened.
+///
+/// Converts into an [std::io::Error] that wraps it, use [ReadError::find] to get it back.
+#[derive(Debug)]
+pub struct ReadError {
+    pub kind: ReadErrorKind,
+    pub path: Option<PathBuf>,
+    /// The 1-based line number, `None` if the file could not be opened.
+    pub line: Option<u64>,
+    /// The offset of the start of the line in the decompressed data.
+    pub offset: u64,
+    pub source: Box<dyn Error + Send + Sync>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadErrorKind {
+    /// The file could not be opened or is not zstd compressed.
+    Open,
+    /// Reading or decompressing failed.
+    Io,
+    Utf8,
+    Json,
+}
+
+/// An [ErrorKind::InvalidInput] error, for invalid configs and arguments.
+pub(crate) fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidInput, message)
+}
+
+impl ReadError {
+    pub fn open(path: Option<PathBuf>, source: std::io::Error) -> Self {
+        ReadError {
+            kind: ReadErrorKind::Open,
+            path,
+            line: None,
+            offset: 0,
+            source: Box::new(source),
+        }
+    }
+
+    /// The [ReadError] wrapped by `err`, if any.
+    pub fn find(err: &std::io::Error) -> Option<&ReadError> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    /// Whether the data itself is invalid, as opposed to failing to read it.
+    pub fn is_invalid_data(&self) -> bool {
+        matches!(self.kind, ReadErrorKind::Utf8 | ReadErrorKind::Json)
+    }
+}
+
+impl ReadErrorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ReadErrorKind::Open => "open",
+            ReadErrorKind::Io => "io",
+            ReadErrorKind::Utf8 => "utf8",
+            ReadErrorKind::Json => "json",
+        }
+    }
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let what = match self.kind {
+            ReadErrorKind::Open => "Failed to open",
+            ReadErrorKind::Io => "Failed to read",
+            ReadErrorKind::Utf8 => "Invalid UTF-8 in",
+            ReadErrorKind::Json => "Invalid JSON sample in",
+        };
+        write!(f, "{} ", what)?;
+        match &self.path {
+            Some(path) => write!(f, "{:?}", path)?,
+            None => write!(f, "input")?,
+        }
+        if let Some(line) = self.line {
+            write!(f, " at line {} (offset {})", line, self.offset)?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
+impl Error for ReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+impl From<ReadError> for std::io::Error {
+    fn from(err: ReadError) -> Self {
+        let kind = if err.is_invalid_data() {
+            ErrorKind::InvalidData
+        } else {
+            err.source
+                .downcast_ref::<std::io::Error>()
+                .map_or(ErrorKind::Other, |e| e.kind())
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, ErrorKind};
+
+    use crate::error::{ReadError, ReadErrorKind};
+    use crate::normalize::Normalization;
+    use crate::pipeline::Pipeline;
+    use crate::sample::{SampleLines, SampleReader};
+
+    #[test]
+    fn location() {
+        let good = r#"{"text": "a", "meta": {"pile_set_name": "Pile-CC"}}"#;
+        let invalid_utf8 = b"{\"text\": \"\xff\xfe\"}";
+        let mut data = format!("{}\n{}\n", good, good).into_bytes();
+        data.extend_from_slice(invalid_utf8);
+        data.extend_from_slice(format!("\n{{\"text\": 3}}\n{}\n", good).as_bytes());
+        let reader = SampleReader::new(Cursor::new(data), Pipeline::empty(), Normalization::NONE)
+            .with_path("data.jsonl.zst".into());
+
+        let results: Vec<_> = reader.collect();
+        assert_eq!(results.len(), 5);
+        assert!(results[0].is_ok() && results[1].is_ok() && results[4].is_ok());
+
+        let err = results[2].as_ref().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let err = ReadError::find(err).unwrap();
+        assert_eq!(err.kind, ReadErrorKind::Utf8);
+        assert_eq!(err.path.as_ref().unwrap().to_str(), Some("data.jsonl.zst"));
+        assert_eq!(err.line, Some(3));
+        let offset = 2 * (good.len() as u64 + 1);
+        assert_eq!(err.offset, offset);
+
+        // the invalid line still counts towards the offset of the next one
+        let err = ReadError::find(results[3].as_ref().unwrap_err()).unwrap();
+        assert_eq!(err.kind, ReadErrorKind::Json);
+        assert_eq!(err.line, Some(4));
+        assert_eq!(err.offset, offset + invalid_utf8.len() as u64 + 1);
+    }
+
+    #[test]
+    fn sample_lines() {
+        let good = r#"{"text": "a", "meta": {"pile_set_name": "Pile-CC"}}"#;
+        let data = format!("{}\n\n  \n{}\r\n{{\"text\": 3}}\n", good, good);
+        let path =
+            std::env::temp_dir().join(format!("kt-lines-test-{}.jsonl.zst", std::process::id()));
+        std::fs::write(&path, zstd::encode_all(data.as_bytes(), 0).unwrap()).unwrap();
+
+        let results: Vec<_> = SampleLines::open(&path).unwrap().collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().0, good);
+        assert_eq!(results[1].as_ref().unwrap().0, good);
+
+        let err = ReadError::find(results[2].as_ref().unwrap_err()).unwrap();
+        assert_eq!(err.kind, ReadErrorKind::Json);
+        assert_eq!(err.path.as_ref(), Some(&path));
+        assert_eq!(err.line, Some(5));
+        assert_eq!(err.offset, 2 * good.len() as u64 + 7);
+    }
+}
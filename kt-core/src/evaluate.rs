@@ -0,0 +1,530 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+use aho_corasick::AhoCorasick;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::batch::build_tokenizer;
+use crate::error::invalid_input;
+use crate::normalize::Normalization;
+use crate::sample::Sample;
+
+/// Raw counts for a set of tokenized samples.
+#[derive(Debug, Default, Copy, Clone, Serialize)]
+pub struct TextCounts {
+    pub samples: u64,
+    pub bytes: u64,
+    pub chars: u64,
+    pub words: u64,
+    pub tokens: u64,
+    /// Bytes of the normalized text that are not covered by any token and were dropped.
+    pub skipped_bytes: u64,
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Compression {
+    #[serde(flatten)]
+    pub counts: TextCounts,
+    pub bytes_per_token: f64,
+    pub chars_per_token: f64,
+    pub words_per_token: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenUsage {
+    pub index: usize,
+    /// The token bytes, decoded lossily for readability.
+    pub token: String,
+    pub bytes: Vec<u8>,
+    pub count: u64,
+    pub fraction: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VocabReport {
+    pub vocab_size: usize,
+    pub unused_tokens: usize,
+    pub unused_fraction: f64,
+
+    pub total: Compression,
+    pub per_set: BTreeMap<String, Compression>,
+
+    /// Number of vocab tokens for each token length in bytes.
+    pub length_histogram: BTreeMap<usize, usize>,
+    /// Number of tokenized tokens for each token length in bytes.
+    pub used_length_histogram: BTreeMap<usize, u64>,
+
+    pub most_common: Vec<TokenUsage>,
+    /// The least common tokens that are still used at least once.
+    pub rarest: Vec<TokenUsage>,
+}
+
+/// The difference between two vocabs evaluated on the same samples.
+#[derive(Debug, Clone, Serialize)]
+pub struct VocabDiff {
+    pub shared: usize,
+    /// Tokens only in the second vocab, sorted by usage.
+    pub added: Vec<TokenUsage>,
+    /// Tokens only in the first vocab, sorted by usage.
+    pub removed: Vec<TokenUsage>,
+    /// The shared tokens with the largest change in usage rank.
+    pub rank_changes: Vec<RankChange>,
+
+    pub total: CompressionDiff,
+    pub per_set: BTreeMap<String, CompressionDiff>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RankChange {
+    pub token: String,
+    pub bytes: Vec<u8>,
+    pub rank_a: usize,
+    pub rank_b: usize,
+    pub count_a: u64,
+    pub count_b: u64,
+}
+
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct CompressionDiff {
+    pub a: Compression,
+    pub b: Compression,
+    /// Relative change in bytes per token from `a` to `b`, positive means `b` compresses better.
+    pub bytes_per_token_change: f64,
+}
+
+/// Tokenizes samples and collects compression and token usage statistics.
+///
+/// Samples should be passed un-normalized, the text counts are based on the original text
+/// and each evaluator applies the normalization of its own vocab before tokenizing.
+pub struct VocabEvaluator {
+    tokens: Vec<Vec<u8>>,
+    normalization: Normalization,
+    aho: AhoCorasick,
+    text_norm: String,
+
+    usage: Vec<u64>,
+    total: TextCounts,
+    per_set: BTreeMap<String, TextCounts>,
+}
+
+impl TextCounts {
+    fn add(&mut self, other: &TextCounts) {
+        self.samples += other.samples;
+        self.bytes += other.bytes;
+        self.chars += other.chars;
+        self.words += other.words;
+        self.tokens += other.tokens;
+        self.skipped_bytes += other.skipped_bytes;
+    }
+
+    pub fn compression(self) -> Compression {
+        let per_token = |x: u64| x as f64 / self.tokens.max(1) as f64;
+        Compression {
+            counts: self,
+            bytes_per_token: per_token(self.bytes),
+            chars_per_token: per_token(self.chars),
+            words_per_token: per_token(self.words),
+        }
+    }
+}
+
+impl VocabEvaluator {
+    pub fn new(tokens: Vec<Vec<u8>>, normalization: Normalization) -> Self {
+        VocabEvaluator {
+            aho: build_tokenizer(&tokens),
+            usage: vec![0; tokens.len()],
+            tokens,
+            normalization,
+            text_norm: String::new(),
+            total: TextCounts::default(),
+            per_set: BTreeMap::default(),
+        }
+    }
+
+    pub fn add_sample(&mut self, sample: &Sample) {
+        let text = &sample.text;
+
+        let mut counts = TextCounts {
+            samples: 1,
+            bytes: text.len() as u64,
+            chars: text.chars().count() as u64,
+            words: text.split_whitespace().count() as u64,
+            tokens: 0,
+            skipped_bytes: 0,
+        };
+        self.text_norm.clear();
+        self.normalization.apply_into(text, &mut self.text_norm);
+
+        let mut matched_bytes = 0;
+        for m in self.aho.find_iter(&self.text_norm) {
+            self.usage[m.pattern()] += 1;
+            counts.tokens += 1;
+            matched_bytes += m.len();
+        }
+        counts.skipped_bytes = (self.text_norm.len() - matched_bytes) as u64;
+
+        self.total.add(&counts);
+        self.per_set
+            .entry(sample.meta.pile_set_name.clone())
+            .or_default()
+            .add(&counts);
+    }
+
+    pub fn tokens(&self) -> &[Vec<u8>] {
+        &self.tokens
+    }
+
+    /// How often each token has been used so far.
+    pub fn usage(&self) -> &[u64] {
+        &self.usage
+    }
+
+    pub fn total(&self) -> TextCounts {
+        self.total
+    }
+
+    pub fn per_set(&self) -> &BTreeMap<String, TextCounts> {
+        &self.per_set
+    }
+
+    /// The usage rank of each token, the most common token has rank 0.
+    pub fn ranks(&self) -> Vec<usize> {
+        let mut ranks = vec![0; self.tokens.len()];
+        let sorted = (0..self.tokens.len()).sorted_by_key(|&i| (Reverse(self.usage[i]), i));
+        for (rank, i) in sorted.enumerate() {
+            ranks[i] = rank;
+        }
+        ranks
+    }
+
+    pub fn token_usage(&self, index: usize) -> TokenUsage {
+        let count = self.usage[index];
+        TokenUsage {
+            index,
+            token: String::from_utf8_lossy(&self.tokens[index]).into_owned(),
+            bytes: self.tokens[index].clone(),
+            count,
+            fraction: count as f64 / self.total.tokens as f64,
+        }
+    }
+
+    /// Build the report, listing the `top` most common and rarest tokens.
+    pub fn report(&self, top: usize) -> VocabReport {
+        let vocab_size = self.tokens.len();
+        let unused_tokens = self.usage.iter().filter(|&&c| c == 0).count();
+
+        let mut length_histogram = BTreeMap::new();
+        let mut used_length_histogram = BTreeMap::new();
+        for (token, &count) in self.tokens.iter().zip(&self.usage) {
+            *length_histogram.entry(token.len()).or_insert(0) += 1;
+            *used_length_histogram.entry(token.len()).or_insert(0) += count;
+        }
+
+        let used = (0..vocab_size)
+            .filter(|&i| self.usage[i] > 0)
+            .sorted_by_key(|&i| (Reverse(self.usage[i]), i))
+            .collect_vec();
+        let most_common = used
+            .iter()
+            .take(top)
+            .map(|&i| self.token_usage(i))
+            .collect();
+        let rarest = used
+            .iter()
+            .rev()
+            .take(top)
+            .map(|&i| self.token_usage(i))
+            .collect();
+
+        VocabReport {
+            vocab_size,
+            unused_tokens,
+            unused_fraction: unused_tokens as f64 / vocab_size as f64,
+            total: self.total.compression(),
+            per_set: self
+                .per_set
+                .iter()
+                .map(|(name, counts)| (name.clone(), counts.compression()))
+                .collect(),
+            length_histogram,
+            used_length_histogram,
+            most_common,
+            rarest,
+        }
+    }
+}
+
+impl CompressionDiff {
+    pub fn new(a: TextCounts, b: TextCounts) -> Self {
+        let a = a.compression();
+        let b = b.compression();
+        // no change if there was nothing to tokenize
+        let bytes_per_token_change = if a.bytes_per_token > 0.0 {
+            b.bytes_per_token / a.bytes_per_token - 1.0
+        } else {
+            0.0
+        };
+        CompressionDiff {
+            a,
+            b,
+            bytes_per_token_change,
+        }
+    }
+}
+
+/// Compare two vocabs that have been evaluated on the same samples.
+/// Only the `top` shared tokens with the largest rank change are listed.
+///
+/// Fails if the evaluators have seen different samples.
+/// Sets that only one of them has seen are left out of `per_set`.
+pub fn diff_vocabs(
+    a: &VocabEvaluator,
+    b: &VocabEvaluator,
+    top: usize,
+) -> std::io::Result<VocabDiff> {
+    let seen = |x: &VocabEvaluator| (x.total.samples, x.total.bytes, x.total.chars);
+    if seen(a) != seen(b) {
+        return Err(invalid_input(format!(
+            "Vocabs must be evaluated on the same samples, got (samples, bytes, chars) {:?} and {:?}",
+            seen(a),
+            seen(b)
+        )));
+    }
+
+    let index_a: HashMap<&[u8], usize> = a
+        .tokens
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.as_slice(), i))
+        .collect();
+    let index_b: HashMap<&[u8], usize> = b
+        .tokens
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.as_slice(), i))
+        .collect();
+
+    let only_in = |x: &VocabEvaluator, other: &HashMap<&[u8], usize>| {
+        (0..x.tokens.len())
+            .filter(|&i| !other.contains_key(x.tokens[i].as_slice()))
+            .sorted_by_key(|&i| (Reverse(x.usage[i]), i))
+            .map(|i| x.token_usage(i))
+            .collect_vec()
+    };
+    let added = only_in(b, &index_a);
+    let removed = only_in(a, &index_b);
+
+    let ranks_a = a.ranks();
+    let ranks_b = b.ranks();
+    let shared = (0..a.tokens.len())
+        .filter_map(|ia| index_b.get(a.tokens[ia].as_slice()).map(|&ib| (ia, ib)))
+        .collect_vec();
+    let rank_changes = shared
+        .iter()
+        .sorted_by_key(|&&(ia, ib)| (Reverse(ranks_a[ia].abs_diff(ranks_b[ib])), ia))
+        .take(top)
+        .map(|&(ia, ib)| RankChange {
+            token: String::from_utf8_lossy(&a.tokens[ia]).into_owned(),
+            bytes: a.tokens[ia].clone(),
+            rank_a: ranks_a[ia],
+            rank_b: ranks_b[ib],
+            count_a: a.usage[ia],
+            count_b: b.usage[ib],
+        })
+        .collect();
+
+    let per_set = a
+        .per_set
+        .iter()
+        .filter_map(|(name, &counts_a)| {
+            let &counts_b = b.per_set.get(name)?;
+            Some((name.clone(), CompressionDiff::new(counts_a, counts_b)))
+        })
+        .collect();
+
+    Ok(VocabDiff {
+        shared: shared.len(),
+        added,
+        removed,
+        rank_changes,
+        total: CompressionDiff::new(a.total, b.total),
+        per_set,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::evaluate::{diff_vocabs, CompressionDiff, TextCounts, VocabEvaluator};
+    use crate::normalize::Normalization;
+    use crate::sample::{Meta, Sample};
+
+    fn sample(text: &str, set: &str) -> Sample {
+        Sample {
+            text: text.to_owned(),
+            meta: Meta {
+                pile_set_name: set.to_owned(),
+                extra: Default::default(),
+            },
+        }
+    }
+
+    fn evaluator(tokens: &[&str]) -> VocabEvaluator {
+        let tokens = tokens.iter().map(|t| t.as_bytes().to_vec()).collect();
+        VocabEvaluator::new(tokens, Normalization::NONE)
+    }
+
+    #[test]
+    fn report() {
+        let mut evaluator = evaluator(&["a", "b", "ab", " ", "zz"]);
+        // "ab", " ", "a"
+        evaluator.add_sample(&sample("ab a", "X"));
+        // "b", " ", "b", with the two bytes of "é" skipped
+        evaluator.add_sample(&sample("bé b", "Y"));
+
+        let report = evaluator.report(2);
+        assert_eq!(report.vocab_size, 5);
+        assert_eq!(report.unused_tokens, 1);
+        assert_eq!(report.unused_fraction, 0.2);
+
+        let total = report.total;
+        assert_eq!(total.counts.samples, 2);
+        assert_eq!(total.counts.bytes, 9);
+        assert_eq!(total.counts.chars, 8);
+        assert_eq!(total.counts.words, 4);
+        assert_eq!(total.counts.tokens, 6);
+        assert_eq!(total.counts.skipped_bytes, 2);
+        assert_eq!(total.bytes_per_token, 1.5);
+        assert_eq!(total.words_per_token, 4.0 / 6.0);
+
+        let x = report.per_set["X"];
+        assert_eq!(
+            (x.counts.bytes, x.counts.tokens, x.counts.skipped_bytes),
+            (4, 3, 0)
+        );
+        let y = report.per_set["Y"];
+        assert_eq!(
+            (y.counts.bytes, y.counts.tokens, y.counts.skipped_bytes),
+            (5, 3, 2)
+        );
+        assert_eq!(y.chars_per_token, 4.0 / 3.0);
+
+        assert_eq!(evaluator.usage(), &[1, 2, 1, 2, 0]);
+        assert_eq!(evaluator.ranks(), vec![2, 0, 3, 1, 4]);
+        let listed = |usages: &[crate::evaluate::TokenUsage]| {
+            usages
+                .iter()
+                .map(|u| (u.token.clone(), u.count))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            listed(&report.most_common),
+            [("b".to_owned(), 2), (" ".to_owned(), 2)]
+        );
+        assert_eq!(report.most_common[0].fraction, 2.0 / 6.0);
+        assert_eq!(
+            listed(&report.rarest),
+            [("ab".to_owned(), 1), ("a".to_owned(), 1)]
+        );
+
+        assert_eq!(report.length_histogram, BTreeMap::from([(1, 3), (2, 2)]));
+        assert_eq!(
+            report.used_length_histogram,
+            BTreeMap::from([(1, 5), (2, 1)])
+        );
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn compression_diff() {
+        let counts = |bytes, tokens| TextCounts {
+            samples: 1,
+            bytes,
+            chars: bytes,
+            words: 1,
+            tokens,
+            skipped_bytes: 0,
+        };
+        let diff = CompressionDiff::new(counts(12, 6), counts(12, 4));
+        assert_eq!(diff.a.bytes_per_token, 2.0);
+        assert_eq!(diff.b.bytes_per_token, 3.0);
+        assert_close(diff.bytes_per_token_change, 0.5);
+
+        let diff = CompressionDiff::new(counts(12, 4), counts(12, 6));
+        assert_close(diff.bytes_per_token_change, -1.0 / 3.0);
+
+        let diff = CompressionDiff::new(counts(0, 0), counts(0, 0));
+        assert_eq!(diff.a.bytes_per_token, 0.0);
+        assert_eq!(diff.bytes_per_token_change, 0.0);
+    }
+
+    #[test]
+    fn diff() {
+        let mut a = evaluator(&["a", "b", "ab", " "]);
+        let mut b = evaluator(&["a", "b", " ", "ba"]);
+        for sample in [sample("ab ab", "X"), sample("ba", "Y")] {
+            a.add_sample(&sample);
+            b.add_sample(&sample);
+        }
+        assert_eq!(a.usage(), &[1, 1, 2, 1]);
+        assert_eq!(b.usage(), &[2, 2, 1, 1]);
+
+        let diff = diff_vocabs(&a, &b, 2).unwrap();
+        assert_eq!(diff.shared, 3);
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(
+            (diff.added[0].token.as_str(), diff.added[0].count),
+            ("ba", 1)
+        );
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(
+            (diff.removed[0].token.as_str(), diff.removed[0].count),
+            ("ab", 2)
+        );
+
+        // every shared token moves up one rank because "ab" was the most common in `a`
+        let changes = diff
+            .rank_changes
+            .iter()
+            .map(|c| (c.token.as_str(), c.rank_a, c.rank_b, c.count_a, c.count_b))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, [("a", 1, 0, 1, 2), ("b", 2, 1, 1, 2)]);
+
+        assert_eq!(diff.total.a.counts.tokens, 5);
+        assert_eq!(diff.total.b.counts.tokens, 6);
+        assert_close(
+            diff.total.bytes_per_token_change,
+            (7.0 / 6.0) / (7.0 / 5.0) - 1.0,
+        );
+        assert_eq!(diff.per_set.len(), 2);
+        assert_close(diff.per_set["X"].bytes_per_token_change, -0.4);
+        assert_close(diff.per_set["Y"].bytes_per_token_change, 1.0);
+    }
+
+    #[test]
+    fn diff_different_samples() {
+        let mut a = evaluator(&["a", "b"]);
+        let mut b = evaluator(&["a", "b"]);
+        a.add_sample(&sample("ab", "X"));
+        b.add_sample(&sample("ab", "Z"));
+
+        // same text under different sets, only the total can be compared
+        let diff = diff_vocabs(&a, &b, 10).unwrap();
+        assert!(diff.per_set.is_empty());
+        assert_close(diff.total.bytes_per_token_change, 0.0);
+
+        b.add_sample(&sample("ab", "X"));
+        let err = diff_vocabs(&a, &b, 10).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
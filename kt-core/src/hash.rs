@@ -0,0 +1,23 @@
+/// A fast non-cryptographic hash that does not depend on the platform or the Rust version,
+/// so anything derived from it is reproducible.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    // FNV-1a followed by a finalizer to spread the entropy over all bits
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    mix64(hash)
+}
+
+/// The splitmix64 finalizer, a bijection on `u64` with good avalanche behaviour.
+pub fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Combine two hashes in an order-dependent way.
+pub fn combine(a: u64, b: u64) -> u64 {
+    mix64(a.rotate_left(5) ^ b)
+}
@@ -2,4 +2,22 @@ pub mod iter;
 pub mod unicode;
 
 pub mod batch;
+pub mod bigram;
+pub mod clean;
+pub mod decontam;
+pub mod dedup;
+pub mod error;
+pub mod evaluate;
+pub mod hash;
+pub mod normalize;
+pub mod pipeline;
+pub mod prune;
+pub mod quality;
 pub mod sample;
+pub mod schedule;
+pub mod script;
+pub mod sequential;
+pub mod shard;
+pub mod shuffle;
+pub mod split;
+pub mod vocab;
@@ -0,0 +1,337 @@
+use std::fmt::{Display, Formatter};
+use std::iter::once;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::char::canonical_combining_class;
+use unicode_normalization::{
+    is_nfc_quick, is_nfd_quick, is_nfkc_quick, is_nfkd_quick, IsNormalized, UnicodeNormalization,
+};
+
+/// The text normalization applied before tokenization.
+///
+/// This is stored in the vocab so training and inference always normalize the same way.
+/// The string form is the normalization form optionally followed by flags, eg. `"nfkc+casefold+collapse"`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Normalization {
+    pub form: NormalizationForm,
+    /// Map every char to lowercase.
+    #[serde(default)]
+    pub case_fold: bool,
+    /// Replace each run of whitespace by a single newline if it contains one, otherwise by a single space.
+    #[serde(default)]
+    pub collapse_whitespace: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalizationForm {
+    None,
+    Nfc,
+    Nfkc,
+    Nfd,
+    Nfkd,
+}
+
+/// Maps byte ranges in a normalized string back to byte ranges in the original string.
+///
+/// The original string is split into segments that can be normalized independently,
+/// offsets within a segment that changed during normalization are rounded outwards to the segment bounds.
+#[derive(Debug, Clone)]
+pub struct OffsetMap {
+    segments: Vec<Segment>,
+    norm_len: usize,
+    orig_len: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Segment {
+    norm_start: usize,
+    orig_start: usize,
+    // whether normalization left this segment unchanged
+    identical: bool,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization::NFC
+    }
+}
+
+impl Normalization {
+    pub const NONE: Normalization = Normalization::new(NormalizationForm::None);
+    pub const NFC: Normalization = Normalization::new(NormalizationForm::Nfc);
+
+    pub const fn new(form: NormalizationForm) -> Self {
+        Normalization {
+            form,
+            case_fold: false,
+            collapse_whitespace: false,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        *self == Normalization::NONE
+    }
+
+    pub fn apply(&self, s: &str) -> String {
+        let mut result = String::with_capacity(s.len());
+        self.apply_into(s, &mut result);
+        result
+    }
+
+    /// Normalize `s` and append the result to `out`.
+    pub fn apply_into(&self, s: &str, out: &mut String) {
+        let start = out.len();
+
+        if self.case_fold {
+            let folded = s.chars().flat_map(char::to_lowercase).collect::<String>();
+            self.form.apply_into(&folded, out);
+        } else {
+            self.form.apply_into(s, out);
+        }
+
+        if self.collapse_whitespace {
+            let normalized = out.split_off(start);
+            collapse_whitespace_into(&normalized, out);
+        }
+    }
+
+    /// Normalize `s`, also returning the mapping back to the original offsets.
+    pub fn apply_with_offsets(&self, s: &str) -> (String, OffsetMap) {
+        let mut norm = String::with_capacity(s.len());
+        let mut segments: Vec<Segment> = vec![];
+
+        let mut start = 0;
+        let mut prev = None;
+        for (i, c) in s.char_indices() {
+            if let Some(prev) = prev {
+                if self.is_boundary(prev, c) {
+                    self.push_segment(&mut norm, &mut segments, &s[start..i], start);
+                    start = i;
+                }
+            }
+            prev = Some(c);
+        }
+        if start < s.len() {
+            self.push_segment(&mut norm, &mut segments, &s[start..], start);
+        }
+
+        let map = OffsetMap {
+            segments,
+            norm_len: norm.len(),
+            orig_len: s.len(),
+        };
+        (norm, map)
+    }
+
+    /// Whether normalization can never interact across the boundary between `prev` and `c`.
+    fn is_boundary(&self, prev: char, c: char) -> bool {
+        if self.collapse_whitespace && prev.is_whitespace() && c.is_whitespace() {
+            return false;
+        }
+        if c.is_ascii() {
+            return true;
+        }
+
+        let starter = canonical_combining_class(c) == 0;
+        match self.form {
+            NormalizationForm::None => true,
+            NormalizationForm::Nfd | NormalizationForm::Nfkd => starter,
+            NormalizationForm::Nfc => starter && is_nfc_quick(once(c)) != IsNormalized::Maybe,
+            NormalizationForm::Nfkc => starter && is_nfkc_quick(once(c)) != IsNormalized::Maybe,
+        }
+    }
+
+    fn push_segment(
+        &self,
+        norm: &mut String,
+        segments: &mut Vec<Segment>,
+        orig: &str,
+        orig_start: usize,
+    ) {
+        let norm_start = norm.len();
+        self.apply_into(orig, norm);
+        let identical = &norm[norm_start..] == orig;
+
+        // consecutive unchanged segments can be merged, the offsets within them map one-to-one
+        if identical && segments.last().is_some_and(|s| s.identical) {
+            return;
+        }
+
+        segments.push(Segment {
+            norm_start,
+            orig_start,
+            identical,
+        });
+    }
+}
+
+impl NormalizationForm {
+    fn apply_into(self, s: &str, out: &mut String) {
+        let quick = match self {
+            NormalizationForm::None => IsNormalized::Yes,
+            NormalizationForm::Nfc => is_nfc_quick(s.chars()),
+            NormalizationForm::Nfkc => is_nfkc_quick(s.chars()),
+            NormalizationForm::Nfd => is_nfd_quick(s.chars()),
+            NormalizationForm::Nfkd => is_nfkd_quick(s.chars()),
+        };
+        if quick == IsNormalized::Yes {
+            out.push_str(s);
+            return;
+        }
+
+        match self {
+            NormalizationForm::None => unreachable!(),
+            NormalizationForm::Nfc => out.extend(s.nfc()),
+            NormalizationForm::Nfkc => out.extend(s.nfkc()),
+            NormalizationForm::Nfd => out.extend(s.nfd()),
+            NormalizationForm::Nfkd => out.extend(s.nfkd()),
+        }
+    }
+}
+
+fn collapse_whitespace_into(s: &str, out: &mut String) {
+    let mut run: Option<char> = None;
+    for c in s.chars() {
+        if c.is_whitespace() {
+            if c == '\n' || run.is_none() {
+                run = Some(if c == '\n' { '\n' } else { ' ' });
+            }
+        } else {
+            if let Some(run) = run.take() {
+                out.push(run);
+            }
+            out.push(c);
+        }
+    }
+    if let Some(run) = run {
+        out.push(run);
+    }
+}
+
+impl OffsetMap {
+    /// Map the non-empty range `start..end` in the normalized string to a range in the original string.
+    pub fn map_range(&self, start: usize, end: usize) -> (usize, usize) {
+        assert!(start < end && end <= self.norm_len);
+
+        let start_index = self.segment_index(start);
+        let start_segment = self.segments[start_index];
+        let orig_start = if start_segment.identical {
+            start_segment.orig_start + (start - start_segment.norm_start)
+        } else {
+            start_segment.orig_start
+        };
+
+        let end_index = self.segment_index(end - 1);
+        let end_segment = self.segments[end_index];
+        let orig_end = if end_segment.identical {
+            end_segment.orig_start + (end - end_segment.norm_start)
+        } else {
+            self.segments
+                .get(end_index + 1)
+                .map_or(self.orig_len, |s| s.orig_start)
+        };
+
+        (orig_start, orig_end)
+    }
+
+    /// The index of the segment containing the normalized offset `offset`.
+    fn segment_index(&self, offset: usize) -> usize {
+        self.segments.partition_point(|s| s.norm_start <= offset) - 1
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('+');
+
+        let form = match parts.next().unwrap().to_lowercase().as_str() {
+            "none" => NormalizationForm::None,
+            "nfc" => NormalizationForm::Nfc,
+            "nfkc" => NormalizationForm::Nfkc,
+            "nfd" => NormalizationForm::Nfd,
+            "nfkd" => NormalizationForm::Nfkd,
+            form => return Err(format!("Unknown normalization form {:?}", form)),
+        };
+
+        let mut result = Normalization::new(form);
+        for flag in parts {
+            match flag.to_lowercase().as_str() {
+                "casefold" => result.case_fold = true,
+                "collapse" => result.collapse_whitespace = true,
+                flag => return Err(format!("Unknown normalization flag {:?}", flag)),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+impl Display for Normalization {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let form = match self.form {
+            NormalizationForm::None => "none",
+            NormalizationForm::Nfc => "nfc",
+            NormalizationForm::Nfkc => "nfkc",
+            NormalizationForm::Nfd => "nfd",
+            NormalizationForm::Nfkd => "nfkd",
+        };
+        write!(f, "{}", form)?;
+
+        if self.case_fold {
+            write!(f, "+casefold")?;
+        }
+        if self.collapse_whitespace {
+            write!(f, "+collapse")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::normalize::Normalization;
+
+    #[test]
+    fn combining_accent() {
+        let s = "ae\u{301}b";
+        let (norm, map) = Normalization::NFC.apply_with_offsets(s);
+        assert_eq!(norm, "a\u{e9}b");
+
+        assert_eq!(map.map_range(0, 1), (0, 1));
+        assert_eq!(map.map_range(1, 3), (1, 4));
+        assert_eq!(map.map_range(3, 4), (4, 5));
+        assert_eq!(map.map_range(0, 4), (0, 5));
+    }
+
+    #[test]
+    fn unchanged() {
+        let s = "hello w\u{f6}rld";
+        let (norm, map) = Normalization::NFC.apply_with_offsets(s);
+        assert_eq!(norm, s);
+
+        for start in 0..s.len() {
+            for end in start + 1..=s.len() {
+                assert_eq!(map.map_range(start, end), (start, end));
+            }
+        }
+    }
+
+    #[test]
+    fn fold_and_collapse() {
+        let normalization: Normalization = "nfkc+casefold+collapse".parse().unwrap();
+        assert_eq!(normalization.to_string(), "nfkc+casefold+collapse");
+
+        let s = "Hello \t World\n\n \u{fb01}ne";
+        let (norm, map) = normalization.apply_with_offsets(s);
+        assert_eq!(norm, "hello world\nfine");
+        assert_eq!(norm, normalization.apply(s));
+
+        assert_eq!(map.map_range(6, 11), (8, 13));
+        assert_eq!(map.map_range(5, 6), (5, 8));
+        assert_eq!(map.map_range(12, 14), (16, 19));
+    }
+}
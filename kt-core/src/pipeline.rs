@@ -0,0 +1,350 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::clean::TextCleaner;
+use crate::decontam::{DecontamConfig, NgramIndex};
+use crate::dedup::{DedupConfig, Deduplicator};
+use crate::error::invalid_input;
+use crate::quality::{LineFilter, MojibakeFilter, QualityFilter, WhitespaceCleaner};
+use crate::script::ScriptFilter;
+
+/// A single preprocessing step applied to each sample.
+///
+/// In JSON each stage is an object with a `"stage"` field and the settings of that stage,
+/// missing settings get the same defaults as the corresponding command line args.
+/// For example `{"stage": "quality", "min_chars": 100}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum Stage {
+    Clean(TextCleaner),
+    Script(ScriptFilter),
+    Whitespace(WhitespaceCleaner),
+    Lines(LineFilter),
+    Quality(QualityFilter),
+    Mojibake(MojibakeFilter),
+    /// Drop exact and near-duplicates of earlier samples, this is the only stage that keeps state between samples.
+    Dedup(DedupConfig),
+    Decontam(DecontamConfig),
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct StageCounts {
+    pub samples: u64,
+    pub dropped: u64,
+    pub changed: u64,
+    /// Stage-specific counts, eg. the number of removed chars per class or the number of samples dropped for each reason.
+    pub details: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    counts: Vec<StageCounts>,
+    runners: Vec<StageRunner>,
+}
+
+/// The state a stage needs besides its config.
+#[derive(Debug, Clone)]
+enum StageRunner {
+    Stateless,
+    Dedup(Box<Deduplicator>),
+    // shared between clones of the pipeline, the index can be large and never changes
+    Decontam(Arc<NgramIndex>),
+}
+
+/// Command line args to build a [Pipeline].
+///
+/// By default the pipeline consists of a clean and script stage configured by the corresponding args,
+/// alternatively a JSON file with the list of stages can be passed.
+#[derive(Debug, Clone, clap::Args, Serialize, Deserialize)]
+pub struct PipelineArgs {
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub cleaner: TextCleaner,
+    #[clap(flatten)]
+    #[serde(flatten)]
+    pub script_filter: ScriptFilter,
+
+    /// JSON file containing the list of preprocessing stages, replaces the cleaning and script filter args.
+    ///
+    /// The file contains a list of objects like `{"stage": "quality", "min_chars": 100}`,
+    /// missing settings get their defaults. The stages and their settings:
+    ///
+    /// * `clean`: `classes`, `replacement`, like the cleaning args
+    /// * `script`: `max_rtl_fraction`, `allowed_scripts`, `min_script_fraction`, `strip_rtl_lines`, like the script args
+    /// * `whitespace`: `line_endings`, `trim_lines`, `max_empty_lines`, `trim`
+    /// * `lines`: `min_line_chars`, `remove_repeated`, `max_repeated_fraction`, `boilerplate`
+    /// * `quality`: `min_chars`, `max_chars`, `max_symbol_ratio`, `min_mean_word_length`, `max_mean_word_length`
+    /// * `mojibake`: `max_replacement_fraction`, `max_mojibake_fraction`
+    /// * `dedup`: `exact`, `near`, `shingle`, `shingle_size`, `bands`, `rows`, `memory_mb`, like the dedup tool args
+    /// * `decontam`: `eval`, `ngram`, `min_hits`, `report_only`, like the decontam tool args
+    #[clap(
+        long,
+        verbatim_doc_comment,
+        conflicts_with_all = [
+            "classes", "replacement",
+            "max_rtl_fraction", "allowed_scripts", "min_script_fraction", "strip_rtl_lines"
+        ]
+    )]
+    pub pipeline: Option<PathBuf>,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Clean(_) => "clean",
+            Stage::Script(_) => "script",
+            Stage::Whitespace(_) => "whitespace",
+            Stage::Lines(_) => "lines",
+            Stage::Quality(_) => "quality",
+            Stage::Mojibake(_) => "mojibake",
+            Stage::Dedup(_) => "dedup",
+            Stage::Decontam(_) => "decontam",
+        }
+    }
+
+    // check the config and build the state of the stage
+    fn build(&self) -> std::io::Result<StageRunner> {
+        Ok(match self {
+            Stage::Lines(filter) => {
+                // an empty pattern would match every line
+                if filter.boilerplate.iter().any(|b| b.trim().is_empty()) {
+                    return Err(invalid_input(format!(
+                        "Boilerplate patterns cannot be empty, got {:?}",
+                        filter.boilerplate
+                    )));
+                }
+                StageRunner::Stateless
+            }
+            Stage::Dedup(config) => {
+                StageRunner::Dedup(Box::new(Deduplicator::new(config.clone())?))
+            }
+            Stage::Decontam(config) => StageRunner::Decontam(Arc::new(NgramIndex::load(config)?)),
+            Stage::Clean(_)
+            | Stage::Script(_)
+            | Stage::Whitespace(_)
+            | Stage::Quality(_)
+            | Stage::Mojibake(_) => StageRunner::Stateless,
+        })
+    }
+
+    fn apply(
+        &self,
+        runner: &mut StageRunner,
+        mut text: String,
+        counts: &mut StageCounts,
+    ) -> Option<String> {
+        match (self, runner) {
+            (Stage::Clean(cleaner), _) => {
+                cleaner.apply(&mut text, counts);
+                Some(text)
+            }
+            (Stage::Script(filter), _) => {
+                let len = text.len();
+                let result = filter.apply(text);
+                if result.as_ref().is_some_and(|t| t.len() != len) {
+                    counts.changed += 1;
+                }
+                result
+            }
+            (Stage::Whitespace(cleaner), _) => cleaner.apply(text, counts),
+            (Stage::Lines(filter), _) => filter.apply(text, counts),
+            (Stage::Quality(filter), _) => filter.apply(text, counts),
+            (Stage::Mojibake(filter), _) => filter.apply(text, counts),
+            (Stage::Dedup(_), StageRunner::Dedup(dedup)) => dedup.apply(text, counts),
+            (Stage::Decontam(config), StageRunner::Decontam(index)) => {
+                index.apply(config, text, counts)
+            }
+            (Stage::Dedup(_) | Stage::Decontam(_), _) => {
+                unreachable!("runner does not match stage {}", self.name())
+            }
+        }
+    }
+}
+
+impl StageCounts {
+    pub fn add_detail(&mut self, key: &str, count: u64) {
+        if count == 0 {
+            return;
+        }
+        match self.details.get_mut(key) {
+            Some(value) => *value += count,
+            None => {
+                self.details.insert(key.to_owned(), count);
+            }
+        }
+    }
+}
+
+impl Pipeline {
+    /// Fails if any of the stages has an invalid config or needs a file that can't be loaded.
+    pub fn new(stages: Vec<Stage>) -> std::io::Result<Self> {
+        let counts = vec![StageCounts::default(); stages.len()];
+        let runners = stages
+            .iter()
+            .map(Stage::build)
+            .collect::<std::io::Result<_>>()?;
+        Ok(Pipeline {
+            stages,
+            counts,
+            runners,
+        })
+    }
+
+    pub fn empty() -> Self {
+        Pipeline::new(vec![]).unwrap()
+    }
+
+    /// The old default pipeline, which drops all samples that contain any non-LTR char.
+    pub fn ltr_only() -> Self {
+        Pipeline::new(vec![Stage::Script(ScriptFilter::ltr_only())]).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> std::io::Result<Self> {
+        Pipeline::new(serde_json::from_str(json)?)
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    pub fn counts(&self) -> &[StageCounts] {
+        &self.counts
+    }
+
+    /// Forget the samples seen by the dedup stages, the counters are kept.
+    /// This should be called when starting another pass over the same data.
+    pub fn clear_state(&mut self) {
+        for runner in &mut self.runners {
+            if let StageRunner::Dedup(dedup) = runner {
+                dedup.clear();
+            }
+        }
+    }
+
+    pub fn apply(&mut self, text: String) -> Option<String> {
+        let mut text = text;
+        for ((stage, runner), counts) in self
+            .stages
+            .iter()
+            .zip(&mut self.runners)
+            .zip(&mut self.counts)
+        {
+            counts.samples += 1;
+            text = match stage.apply(runner, text, counts) {
+                Some(text) => text,
+                None => {
+                    counts.dropped += 1;
+                    return None;
+                }
+            };
+        }
+        Some(text)
+    }
+}
+
+impl Display for Pipeline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (stage, counts) in self.stages.iter().zip(&self.counts) {
+            write!(
+                f,
+                "  {}: {} samples, {} dropped, {} changed",
+                stage.name(),
+                counts.samples,
+                counts.dropped,
+                counts.changed
+            )?;
+            for (key, value) in &counts.details {
+                write!(f, ", {}: {}", key, value)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl PipelineArgs {
+    pub fn build(&self) -> std::io::Result<Pipeline> {
+        match &self.pipeline {
+            Some(path) => {
+                let stages = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+                Pipeline::new(stages)
+            }
+            None => {
+                let mut stages = vec![];
+                if !self.cleaner.classes.is_empty() {
+                    stages.push(Stage::Clean(self.cleaner.clone()));
+                }
+                stages.push(Stage::Script(self.script_filter.clone()));
+                Pipeline::new(stages)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pipeline::{Pipeline, Stage};
+    use crate::quality::QualityFilter;
+
+    #[test]
+    fn json_stages() {
+        let json = r#"[
+            {"stage": "clean", "classes": ["bidi-control", "bom"]},
+            {"stage": "script", "max_rtl_fraction": 0.05},
+            {"stage": "whitespace"},
+            {"stage": "quality", "min_chars": 10}
+        ]"#;
+        let mut pipeline = Pipeline::from_json(json).unwrap();
+        assert_eq!(
+            pipeline.stages()[3],
+            Stage::Quality(QualityFilter {
+                min_chars: Some(10),
+                ..QualityFilter::default()
+            })
+        );
+
+        assert_eq!(
+            pipeline.apply("\u{feff}  some longer text\u{202c}  ".to_owned()),
+            Some("some longer text".to_owned())
+        );
+        assert_eq!(pipeline.apply("short".to_owned()), None);
+
+        let counts = pipeline.counts();
+        assert_eq!(counts[0].details["bidi-control"], 1);
+        assert_eq!(counts[2].changed, 1);
+        assert_eq!(counts[3].samples, 2);
+        assert_eq!(counts[3].dropped, 1);
+        assert_eq!(counts[3].details["too_short"], 1);
+    }
+
+    #[test]
+    fn empty_boilerplate() {
+        let json = r#"[{"stage": "lines", "boilerplate": ["Subscribe", " "]}]"#;
+        let err = Pipeline::from_json(json).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let json = r#"[{"stage": "lines", "boilerplate": ["Subscribe"]}]"#;
+        assert!(Pipeline::from_json(json).is_ok());
+    }
+
+    #[test]
+    fn invalid_dedup() {
+        let json = r#"[{"stage": "dedup", "bands": 0}]"#;
+        let err = Pipeline::from_json(json).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn invalid_decontam() {
+        let json = r#"[{"stage": "decontam", "ngram": 0}]"#;
+        let err = Pipeline::from_json(json).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
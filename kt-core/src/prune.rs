@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+
+use aho_corasick::AhoCorasick;
+
+use crate::batch::build_tokenizer;
+
+/// Count how often each token is used when tokenizing the given texts.
+pub fn count_usage<'a>(
+    aho: &AhoCorasick,
+    token_count: usize,
+    texts: impl IntoIterator<Item = &'a str>,
+) -> Vec<u64> {
+    let mut usage = vec![0; token_count];
+    for text in texts {
+        for m in aho.find_iter(text) {
+            usage[m.pattern()] += 1;
+        }
+    }
+    usage
+}
+
+/// Find the tokens that are a proper prefix or suffix of another token, these are the merged tokens
+/// that pruning considers. This only looks at the vocab itself so it works the same during training
+/// and on a finished vocab file, which does not contain the merge history.
+pub fn find_merged(tokens: &[Vec<u8>]) -> Vec<bool> {
+    let mut parts: HashSet<&[u8]> = HashSet::new();
+    for token in tokens {
+        for i in 1..token.len() {
+            parts.insert(&token[..i]);
+            parts.insert(&token[i..]);
+        }
+    }
+
+    tokens
+        .iter()
+        .map(|token| parts.contains(token.as_slice()))
+        .collect()
+}
+
+/// Find merged tokens whose usage has dropped to at most `max_usage_fraction` of all tokens.
+/// Single-byte tokens are never returned, they are needed to tokenize arbitrary text.
+///
+/// Returns `(index, usage)` pairs sorted by index.
+pub fn find_obsolete(
+    tokens: &[Vec<u8>],
+    is_merged: &[bool],
+    usage: &[u64],
+    max_usage_fraction: f64,
+) -> Vec<(usize, u64)> {
+    assert_eq!(tokens.len(), is_merged.len());
+    assert_eq!(tokens.len(), usage.len());
+
+    let total: u64 = usage.iter().sum();
+    let max_usage = (total as f64 * max_usage_fraction) as u64;
+
+    (0..tokens.len())
+        .filter(|&i| tokens[i].len() > 1 && is_merged[i] && usage[i] <= max_usage)
+        .map(|i| (i, usage[i]))
+        .collect()
+}
+
+/// Re-tokenize the given texts with the full vocab and find the tokens that can be removed.
+pub fn prune_vocab<'a>(
+    tokens: &[Vec<u8>],
+    texts: impl IntoIterator<Item = &'a str>,
+    max_usage_fraction: f64,
+) -> Vec<(usize, u64)> {
+    let aho = build_tokenizer(tokens);
+    let usage = count_usage(&aho, tokens.len(), texts);
+    let is_merged = find_merged(tokens);
+    find_obsolete(tokens, &is_merged, &usage, max_usage_fraction)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::prune::prune_vocab;
+
+    #[test]
+    fn prune_merged_prefix() {
+        let tokens: Vec<Vec<u8>> = ["h", "a", "v", "i", "n", "g", " ", "havi", "having"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect();
+
+        let removed = prune_vocab(&tokens, ["having having", "have"], 0.0);
+        assert_eq!(removed, vec![(7, 0)]);
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::str::Chars;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::StageCounts;
+
+/// Cleans up whitespace without changing the meaning of the text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WhitespaceCleaner {
+    /// Replace "\r\n" and lone "\r" line endings by "\n".
+    pub line_endings: bool,
+    pub trim_lines: bool,
+    /// Collapse runs of more than this many empty lines.
+    pub max_empty_lines: Option<usize>,
+    pub trim: bool,
+}
+
+/// Removes repeated and boilerplate lines, and drops samples that consist mostly of repeated lines.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LineFilter {
+    /// Lines with fewer chars (ignoring surrounding whitespace) are never considered repeated,
+    /// this prevents removing short lines like "}" or "end" in code.
+    pub min_line_chars: usize,
+    pub remove_repeated: bool,
+    /// Drop samples where more than this fraction of the line chars are in repeated lines.
+    pub max_repeated_fraction: f64,
+    /// Remove lines containing any of these strings, eg. "All rights reserved".
+    pub boilerplate: Vec<String>,
+}
+
+/// Drops samples based on simple statistics of their content.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QualityFilter {
+    pub min_chars: Option<usize>,
+    pub max_chars: Option<usize>,
+    /// Maximum fraction of non-whitespace chars that are not alphanumeric.
+    pub max_symbol_ratio: Option<f64>,
+    /// Bounds on the mean length of the whitespace-separated words, in chars.
+    pub min_mean_word_length: Option<f64>,
+    pub max_mean_word_length: Option<f64>,
+}
+
+/// Drops samples containing replacement chars or text that looks like UTF-8 decoded as Latin-1 or Windows-1252.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MojibakeFilter {
+    /// Maximum fraction of chars that are U+FFFD.
+    pub max_replacement_fraction: f64,
+    /// Maximum fraction of chars that start a mojibake sequence like "Ã©" or "â€™",
+    /// a run of chars that is valid UTF-8 when encoded as Windows-1252.
+    pub max_mojibake_fraction: f64,
+}
+
+impl Default for WhitespaceCleaner {
+    fn default() -> Self {
+        WhitespaceCleaner {
+            line_endings: true,
+            trim_lines: true,
+            max_empty_lines: Some(2),
+            trim: true,
+        }
+    }
+}
+
+impl WhitespaceCleaner {
+    pub fn apply(&self, text: String, counts: &mut StageCounts) -> Option<String> {
+        let mut result = String::with_capacity(text.len());
+        let mut empty_lines = 0;
+
+        let text_ref = if self.trim { text.trim() } else { &text };
+        let mut lines = split_lines(text_ref, self.line_endings).peekable();
+
+        while let Some((line, ending)) = lines.next() {
+            let line = if self.trim_lines {
+                line.trim_end_matches([' ', '\t'])
+            } else {
+                line
+            };
+
+            if line.is_empty() && lines.peek().is_some() {
+                empty_lines += 1;
+                if self.max_empty_lines.is_some_and(|max| empty_lines > max) {
+                    counts.add_detail("empty_lines", 1);
+                    continue;
+                }
+            } else {
+                empty_lines = 0;
+            }
+
+            result.push_str(line);
+            result.push_str(ending);
+        }
+
+        if result != text {
+            counts.changed += 1;
+        }
+        Some(result)
+    }
+}
+
+// split into lines, keeping the line ending separate and normalizing it to "\n" if `normalize_endings`.
+fn split_lines(text: &str, normalize_endings: bool) -> impl Iterator<Item = (&str, &str)> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        let (line, ending, next) = match rest.find(['\n', '\r']) {
+            None => (rest, "", ""),
+            Some(i) => {
+                let ending_len = if rest[i..].starts_with("\r\n") { 2 } else { 1 };
+                let ending = &rest[i..i + ending_len];
+                (&rest[..i], ending, &rest[i + ending_len..])
+            }
+        };
+        rest = next;
+
+        let ending = if normalize_endings && !ending.is_empty() {
+            "\n"
+        } else {
+            ending
+        };
+        Some((line, ending))
+    })
+}
+
+impl Default for LineFilter {
+    fn default() -> Self {
+        LineFilter {
+            min_line_chars: 20,
+            remove_repeated: true,
+            max_repeated_fraction: 0.3,
+            boilerplate: vec![],
+        }
+    }
+}
+
+impl LineFilter {
+    pub fn apply(&self, text: String, counts: &mut StageCounts) -> Option<String> {
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        let mut total_chars = 0;
+        let mut repeated_chars = 0;
+
+        let mut result = String::with_capacity(text.len());
+        let mut removed_repeated = 0;
+        let mut removed_boilerplate = 0;
+
+        for line in text.split_inclusive('\n') {
+            let key = line.trim();
+            let chars = key.chars().count();
+            total_chars += chars;
+
+            if self.boilerplate.iter().any(|b| key.contains(b.as_str())) {
+                removed_boilerplate += 1;
+                continue;
+            }
+
+            if chars >= self.min_line_chars {
+                let count = seen.entry(key).or_default();
+                *count += 1;
+                if *count > 1 {
+                    repeated_chars += chars;
+                    if self.remove_repeated {
+                        removed_repeated += 1;
+                        continue;
+                    }
+                }
+            }
+
+            result.push_str(line);
+        }
+
+        if total_chars > 0
+            && repeated_chars as f64 / total_chars as f64 > self.max_repeated_fraction
+        {
+            counts.add_detail("too_repetitive", 1);
+            return None;
+        }
+
+        counts.add_detail("repeated_lines", removed_repeated);
+        counts.add_detail("boilerplate_lines", removed_boilerplate);
+        if removed_repeated + removed_boilerplate > 0 {
+            counts.changed += 1;
+        }
+        Some(result)
+    }
+}
+
+impl QualityFilter {
+    pub fn apply(&self, text: String, counts: &mut StageCounts) -> Option<String> {
+        let reason = self.reject_reason(&text);
+        match reason {
+            None => Some(text),
+            Some(reason) => {
+                counts.add_detail(reason, 1);
+                None
+            }
+        }
+    }
+
+    fn reject_reason(&self, text: &str) -> Option<&'static str> {
+        let chars = text.chars().count();
+        if self.min_chars.is_some_and(|min| chars < min) {
+            return Some("too_short");
+        }
+        if self.max_chars.is_some_and(|max| chars > max) {
+            return Some("too_long");
+        }
+
+        if let Some(max_symbol_ratio) = self.max_symbol_ratio {
+            let mut non_whitespace = 0;
+            let mut symbols = 0;
+            for c in text.chars().filter(|c| !c.is_whitespace()) {
+                non_whitespace += 1;
+                if !c.is_alphanumeric() {
+                    symbols += 1;
+                }
+            }
+            if non_whitespace > 0 && symbols as f64 / non_whitespace as f64 > max_symbol_ratio {
+                return Some("symbol_ratio");
+            }
+        }
+
+        if self.min_mean_word_length.is_some() || self.max_mean_word_length.is_some() {
+            let (words, word_chars) = text
+                .split_whitespace()
+                .fold((0, 0), |(n, c), w| (n + 1, c + w.chars().count()));
+            if words > 0 {
+                let mean = word_chars as f64 / words as f64;
+                if self.min_mean_word_length.is_some_and(|min| mean < min)
+                    || self.max_mean_word_length.is_some_and(|max| mean > max)
+                {
+                    return Some("word_length");
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl Default for MojibakeFilter {
+    fn default() -> Self {
+        MojibakeFilter {
+            max_replacement_fraction: 1e-4,
+            max_mojibake_fraction: 1e-4,
+        }
+    }
+}
+
+impl MojibakeFilter {
+    pub fn apply(&self, text: String, counts: &mut StageCounts) -> Option<String> {
+        let mut chars = 0;
+        let mut replacement = 0;
+        let mut mojibake = 0;
+
+        let mut iter = text.chars();
+        while let Some(c) = iter.next() {
+            chars += 1;
+            if c == '\u{fffd}' {
+                replacement += 1;
+            }
+            if is_mojibake_start(c, iter.clone()) {
+                mojibake += 1;
+            }
+        }
+
+        if chars == 0 {
+            return Some(text);
+        }
+        if replacement as f64 / chars as f64 > self.max_replacement_fraction {
+            counts.add_detail("replacement", 1);
+            return None;
+        }
+        if mojibake as f64 / chars as f64 > self.max_mojibake_fraction {
+            counts.add_detail("mojibake", 1);
+            return None;
+        }
+        Some(text)
+    }
+}
+
+// whether `first` and the chars after it are a multi-byte UTF-8 sequence decoded as Windows-1252 (or Latin-1),
+// ie. whether encoding them back to single bytes gives a complete and valid UTF-8 char.
+fn is_mojibake_start(first: char, rest: Chars) -> bool {
+    let lead = match cp1252_byte(first) {
+        Some(lead @ 0xC2..=0xF4) => lead,
+        _ => return false,
+    };
+    let len = match lead {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        _ => 4,
+    };
+
+    let mut bytes = [lead, 0, 0, 0];
+    for (byte, c) in bytes[1..len].iter_mut().zip(rest) {
+        match cp1252_byte(c) {
+            Some(b) => *byte = b,
+            None => return false,
+        }
+    }
+    // missing bytes are left at zero, which is not a valid continuation byte
+    std::str::from_utf8(&bytes[..len]).is_ok()
+}
+
+// the byte `c` was decoded from in Windows-1252, falling back to Latin-1 for the bytes Windows-1252 leaves undefined.
+fn cp1252_byte(c: char) -> Option<u8> {
+    let byte = match c {
+        '\0'..='\u{ff}' => c as u8,
+        '€' => 0x80,
+        '‚' => 0x82,
+        'ƒ' => 0x83,
+        '„' => 0x84,
+        '…' => 0x85,
+        '†' => 0x86,
+        '‡' => 0x87,
+        'ˆ' => 0x88,
+        '‰' => 0x89,
+        'Š' => 0x8A,
+        '‹' => 0x8B,
+        'Œ' => 0x8C,
+        'Ž' => 0x8E,
+        '‘' => 0x91,
+        '’' => 0x92,
+        '“' => 0x93,
+        '”' => 0x94,
+        '•' => 0x95,
+        '–' => 0x96,
+        '—' => 0x97,
+        '˜' => 0x98,
+        '™' => 0x99,
+        'š' => 0x9A,
+        '›' => 0x9B,
+        'œ' => 0x9C,
+        'ž' => 0x9E,
+        'Ÿ' => 0x9F,
+        _ => return None,
+    };
+    Some(byte)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pipeline::StageCounts;
+    use crate::quality::{LineFilter, MojibakeFilter, QualityFilter, WhitespaceCleaner};
+
+    #[test]
+    fn whitespace() {
+        let mut counts = StageCounts::default();
+        let text = "  a  \r\nb\t\n\n\n\n\nc\n\n";
+        let result = WhitespaceCleaner::default().apply(text.to_owned(), &mut counts);
+        assert_eq!(result.unwrap(), "a\nb\n\n\nc");
+        assert_eq!(counts.changed, 1);
+        assert_eq!(counts.details["empty_lines"], 2);
+    }
+
+    #[test]
+    fn repeated_lines() {
+        let filter = LineFilter {
+            boilerplate: vec!["All rights reserved".to_owned()],
+            ..LineFilter::default()
+        };
+        let mut counts = StageCounts::default();
+
+        let text = "Subscribe to our newsletter!\nsome actual content here\n}\n}\nSubscribe to our newsletter!\n(c) 2020, All rights reserved\nmore content\n";
+        let result = filter.apply(text.to_owned(), &mut counts);
+        assert_eq!(
+            result.unwrap(),
+            "Subscribe to our newsletter!\nsome actual content here\n}\n}\nmore content\n"
+        );
+        assert_eq!(counts.details["repeated_lines"], 1);
+        assert_eq!(counts.details["boilerplate_lines"], 1);
+
+        let spam = "Buy cheap watches online now\n".repeat(10);
+        assert_eq!(filter.apply(spam, &mut counts), None);
+    }
+
+    #[test]
+    fn quality_and_mojibake() {
+        let filter = QualityFilter {
+            max_symbol_ratio: Some(0.5),
+            min_mean_word_length: Some(3.0),
+            ..QualityFilter::default()
+        };
+        let mut counts = StageCounts::default();
+        assert!(filter
+            .apply("a normal sentence.".to_owned(), &mut counts)
+            .is_some());
+        assert!(filter.apply("#### ** ##".to_owned(), &mut counts).is_none());
+        assert!(filter
+            .apply("a b c d e f".to_owned(), &mut counts)
+            .is_none());
+        assert_eq!(counts.details["symbol_ratio"], 1);
+        assert_eq!(counts.details["word_length"], 1);
+
+        let filter = MojibakeFilter::default();
+        assert!(filter.apply("café".to_owned(), &mut counts).is_some());
+        assert!(filter.apply("cafÃ©".to_owned(), &mut counts).is_none());
+        assert!(filter.apply("don’t".to_owned(), &mut counts).is_some());
+        assert!(filter.apply("donâ€™t".to_owned(), &mut counts).is_none());
+        assert_eq!(counts.details["mojibake"], 2);
+
+        // accented chars followed by punctuation from the Windows-1252 range are not mojibake
+        for text in [
+            "«café»",
+            "café’s",
+            "voilà…",
+            "é–",
+            "naïve — “quoted”",
+            "Ångström",
+        ] {
+            assert!(
+                filter.apply(text.to_owned(), &mut counts).is_some(),
+                "{:?}",
+                text
+            );
+        }
+        assert!(filter.apply("cafÃ©".to_owned(), &mut counts).is_none());
+        assert!(filter.apply("ðŸ˜€".to_owned(), &mut counts).is_none());
+        assert_eq!(counts.details["mojibake"], 4);
+    }
+}
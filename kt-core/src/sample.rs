@@ -1,71 +1,174 @@
+use std::fs::File;
 use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
 
-use serde::Deserialize;
-use unicode_normalization::UnicodeNormalization;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use zstd::Decoder;
 
-use crate::unicode::str_is_ltr;
+use crate::error::{ReadError, ReadErrorKind};
+use crate::normalize::Normalization;
+use crate::pipeline::Pipeline;
+use crate::split::Splitter;
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Sample {
     pub text: String,
     pub meta: Meta,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Meta {
     pub pile_set_name: String,
+    /// Any other metadata fields, kept so samples can be written back out unchanged.
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 pub struct SampleReader<R: BufRead> {
     reader: R,
-    line: String,
-    remove_rtl: bool,
-    normalize: bool,
+    line: Vec<u8>,
+    pipeline: Pipeline,
+    normalization: Normalization,
+    split: Option<(Splitter, usize)>,
+    path: Option<PathBuf>,
+    line_count: u64,
+    bytes_read: u64,
 }
 
-impl<R: Read> SampleReader<BufReader<Decoder<'static, BufReader<R>>>> {
-    pub fn new_decode(reader: R, remove_rtl: bool, normalize: bool) -> std::io::Result<Self> {
+/// Reads the lines of a zstd compressed JSONL file together with the parsed sample, skipping blank lines.
+/// This is for tools that write the lines back out unchanged, errors include the location like for [SampleReader].
+pub struct SampleLines {
+    reader: BufReader<Decoder<'static, BufReader<File>>>,
+    line: Vec<u8>,
+    path: PathBuf,
+    line_count: u64,
+    bytes_read: u64,
+}
+
+/// A [SampleReader] for a zstd compressed JSONL file.
+pub type DecodeSampleReader<R> = SampleReader<BufReader<Decoder<'static, BufReader<R>>>>;
+
+impl<R: Read> DecodeSampleReader<R> {
+    pub fn new_decode(
+        reader: R,
+        pipeline: Pipeline,
+        normalization: Normalization,
+    ) -> std::io::Result<Self> {
         Ok(Self::new(
             BufReader::new(Decoder::new(reader)?),
-            remove_rtl,
-            normalize,
+            pipeline,
+            normalization,
         ))
     }
 }
 
+impl DecodeSampleReader<File> {
+    /// Open the file at `path`, errors include the path and the line where they happened.
+    pub fn open(
+        path: &Path,
+        pipeline: Pipeline,
+        normalization: Normalization,
+    ) -> Result<Self, ReadError> {
+        let reader = File::open(path)
+            .and_then(|file| Self::new_decode(file, pipeline, normalization))
+            .map_err(|e| ReadError::open(Some(path.to_owned()), e))?;
+        Ok(reader.with_path(path.to_owned()))
+    }
+}
+
 impl<R: BufRead> SampleReader<R> {
-    pub fn new(reader: R, remove_rtl: bool, normalize: bool) -> Self {
+    pub fn new(reader: R, pipeline: Pipeline, normalization: Normalization) -> Self {
         Self {
             reader,
-            line: String::new(),
-            remove_rtl,
-            normalize,
+            line: Vec::new(),
+            pipeline,
+            normalization,
+            split: None,
+            path: None,
+            line_count: 0,
+            bytes_read: 0,
+        }
+    }
+
+    /// The path of the file being read, included in errors.
+    pub fn with_path(mut self, path: PathBuf) -> Self {
+        self.path = Some(path);
+        self
+    }
+
+    /// Only yield the samples assigned to the split with index `split`.
+    /// The split is decided on the raw sample before the pipeline runs, so it does not depend on the pipeline settings.
+    pub fn with_split(mut self, splitter: Splitter, split: usize) -> Self {
+        assert!(split < splitter.names().len());
+        self.split = Some((splitter, split));
+        self
+    }
+
+    /// The preprocessing pipeline, including the counters of each stage.
+    pub fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    /// The number of decompressed bytes read so far, including samples that were filtered out.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn into_pipeline(self) -> Pipeline {
+        self.pipeline
+    }
+
+    fn error(
+        &self,
+        kind: ReadErrorKind,
+        offset: u64,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> ReadError {
+        ReadError {
+            kind,
+            path: self.path.clone(),
+            line: Some(self.line_count),
+            offset,
+            source: source.into(),
         }
     }
 
-    fn next_io(&mut self) -> std::io::Result<Option<Sample>> {
+    fn next_sample(&mut self) -> Result<Option<Sample>, ReadError> {
         loop {
+            let offset = self.bytes_read;
+            self.line_count += 1;
             self.line.clear();
-            self.reader.read_line(&mut self.line)?;
+            if let Err(e) = self.reader.read_until(b'\n', &mut self.line) {
+                return Err(self.error(ReadErrorKind::Io, offset, e));
+            }
+            // count the raw bytes, so the offsets stay correct after an invalid line
+            self.bytes_read += self.line.len() as u64;
 
             if self.line.is_empty() {
                 // EOF reached
                 return Ok(None);
             }
 
-            let mut sample: Sample = serde_json::from_str(&self.line)?;
+            let line = std::str::from_utf8(&self.line)
+                .map_err(|e| self.error(ReadErrorKind::Utf8, offset, e))?;
+            let mut sample: Sample = serde_json::from_str(line)
+                .map_err(|e| self.error(ReadErrorKind::Json, offset, e))?;
 
-            if self.remove_rtl && !str_is_ltr(&sample.text) {
-                // skip RTL text
-                continue;
+            if let Some((splitter, split)) = &self.split {
+                if splitter.split_of(&sample) != *split {
+                    continue;
+                }
             }
 
-            if self.normalize {
-                let text = sample.text.nfc().collect::<String>();
-                sample.text = text;
+            sample.text = match self.pipeline.apply(sample.text) {
+                Some(text) => text,
+                // skip samples dropped by the pipeline, eg. RTL text
+                None => continue,
+            };
+
+            if !self.normalization.is_identity() {
+                sample.text = self.normalization.apply(&sample.text);
             }
 
             return Ok(Some(sample));
@@ -73,10 +176,75 @@ impl<R: BufRead> SampleReader<R> {
     }
 }
 
+impl SampleLines {
+    pub fn open(path: &Path) -> Result<Self, ReadError> {
+        let reader = File::open(path)
+            .and_then(Decoder::new)
+            .map_err(|e| ReadError::open(Some(path.to_owned()), e))?;
+        Ok(SampleLines {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
+            path: path.to_owned(),
+            line_count: 0,
+            bytes_read: 0,
+        })
+    }
+
+    fn error(
+        &self,
+        kind: ReadErrorKind,
+        offset: u64,
+        source: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+    ) -> ReadError {
+        ReadError {
+            kind,
+            path: Some(self.path.clone()),
+            line: Some(self.line_count),
+            offset,
+            source: source.into(),
+        }
+    }
+
+    fn next_line(&mut self) -> Result<Option<(String, Sample)>, ReadError> {
+        loop {
+            let offset = self.bytes_read;
+            self.line_count += 1;
+            self.line.clear();
+            if let Err(e) = self.reader.read_until(b'\n', &mut self.line) {
+                return Err(self.error(ReadErrorKind::Io, offset, e));
+            }
+            self.bytes_read += self.line.len() as u64;
+
+            if self.line.is_empty() {
+                return Ok(None);
+            }
+
+            let line = std::str::from_utf8(&self.line)
+                .map_err(|e| self.error(ReadErrorKind::Utf8, offset, e))?
+                .trim_end_matches(['\n', '\r']);
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let sample: Sample = serde_json::from_str(line)
+                .map_err(|e| self.error(ReadErrorKind::Json, offset, e))?;
+            return Ok(Some((line.to_owned(), sample)));
+        }
+    }
+}
+
+impl Iterator for SampleLines {
+    type Item = std::io::Result<(String, Sample)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_line().map_err(Into::into).transpose()
+    }
+}
+
 impl<R: BufRead> Iterator for SampleReader<R> {
     type Item = std::io::Result<Sample>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next_io().transpose()
+        self.next_sample().map_err(Into::into).transpose()
     }
 }
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::invalid_input;
+
+/// Use `seq_len` and `batch_size` starting from batch index `batch`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduleStep {
+    pub batch: usize,
+    pub seq_len: usize,
+    pub batch_size: usize,
+}
+
+/// A piecewise constant schedule for the sequence length and batch size, eg. for sequence length warmup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    steps: Vec<ScheduleStep>,
+}
+
+impl Schedule {
+    /// The steps are sorted by batch index, which must be unique.
+    pub fn new(mut steps: Vec<ScheduleStep>) -> std::io::Result<Self> {
+        steps.sort_by_key(|s| s.batch);
+
+        for (i, step) in steps.iter().enumerate() {
+            if step.seq_len == 0 || step.batch_size == 0 {
+                return Err(invalid_input(format!(
+                    "Schedule step {:?} has zero seq_len or batch_size",
+                    step
+                )));
+            }
+            if i > 0 && steps[i - 1].batch == step.batch {
+                return Err(invalid_input(format!(
+                    "Schedule has multiple steps for batch {}",
+                    step.batch
+                )));
+            }
+        }
+
+        Ok(Schedule { steps })
+    }
+
+    pub fn steps(&self) -> &[ScheduleStep] {
+        &self.steps
+    }
+
+    /// The step that applies to the batch with the given index, `None` if it is before the first step.
+    pub fn step_at(&self, batch: usize) -> Option<&ScheduleStep> {
+        let index = self.steps.partition_point(|s| s.batch <= batch);
+        index.checked_sub(1).map(|i| &self.steps[i])
+    }
+}
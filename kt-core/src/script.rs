@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use unicode_script::{Script, UnicodeScript};
+
+use crate::unicode::{char_is_ltr, str_is_ltr};
+
+/// The number of chars in each script and bidi direction in a piece of text.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptStats {
+    pub chars: usize,
+    /// Chars rejected by [char_is_ltr], ie. strong RTL chars and explicit bidi formatting chars.
+    pub rtl_chars: usize,
+    pub script_chars: HashMap<Script, usize>,
+}
+
+impl ScriptStats {
+    pub fn new(s: &str) -> Self {
+        let mut stats = ScriptStats::default();
+        stats.add_str(s);
+        stats
+    }
+
+    pub fn add_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.chars += 1;
+            if !char_is_ltr(c) {
+                self.rtl_chars += 1;
+            }
+            *self.script_chars.entry(c.script()).or_default() += 1;
+        }
+    }
+
+    pub fn rtl_fraction(&self) -> f64 {
+        fraction(self.rtl_chars, self.chars)
+    }
+
+    /// The fraction of chars that are in any of the given scripts.
+    pub fn script_fraction(&self, scripts: &[Script]) -> f64 {
+        let count = scripts
+            .iter()
+            .map(|s| self.script_chars.get(s).copied().unwrap_or(0))
+            .sum();
+        fraction(count, self.chars)
+    }
+
+    /// The scripts sorted by decreasing char count.
+    pub fn sorted_scripts(&self) -> Vec<(Script, usize)> {
+        let mut scripts = self
+            .script_chars
+            .iter()
+            .map(|(&s, &c)| (s, c))
+            .collect::<Vec<_>>();
+        scripts.sort_by_key(|&(s, c)| (std::cmp::Reverse(c), s.full_name()));
+        scripts
+    }
+}
+
+/// Decides which samples to keep based on the scripts and bidi direction of their chars.
+///
+/// The defaults of the command line args keep the old behaviour of dropping any sample with a non-LTR char.
+#[derive(Debug, Clone, PartialEq, clap::Args, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptFilter {
+    /// Drop samples where more than this fraction of the chars is RTL or bidi formatting.
+    #[clap(long, default_value_t = 0.0)]
+    pub max_rtl_fraction: f64,
+    /// Scripts counted by `min_script_fraction`, eg. "Latin,Common". Empty means no script check.
+    #[clap(long, value_delimiter = ',', value_parser = parse_script)]
+    #[serde(with = "script_names")]
+    pub allowed_scripts: Vec<Script>,
+    /// Drop samples where less than this fraction of the chars is in `allowed_scripts`.
+    #[clap(long, default_value_t = 1.0)]
+    pub min_script_fraction: f64,
+    /// Remove the lines that contain RTL chars before checking the rest of the sample.
+    #[clap(long)]
+    pub strip_rtl_lines: bool,
+}
+
+impl Default for ScriptFilter {
+    fn default() -> Self {
+        ScriptFilter::ltr_only()
+    }
+}
+
+impl ScriptFilter {
+    /// Keep everything.
+    pub fn accept_all() -> Self {
+        ScriptFilter {
+            max_rtl_fraction: 1.0,
+            allowed_scripts: vec![],
+            min_script_fraction: 0.0,
+            strip_rtl_lines: false,
+        }
+    }
+
+    /// Drop all samples that contain any non-LTR char.
+    pub fn ltr_only() -> Self {
+        ScriptFilter {
+            max_rtl_fraction: 0.0,
+            ..ScriptFilter::accept_all()
+        }
+    }
+
+    /// Filter a single sample, returning `None` if it should be dropped.
+    pub fn apply(&self, text: String) -> Option<String> {
+        let text = if self.strip_rtl_lines {
+            let stripped = strip_rtl_lines(&text);
+            if stripped.is_empty() && !text.is_empty() {
+                return None;
+            }
+            stripped
+        } else {
+            text
+        };
+
+        let check_rtl = self.max_rtl_fraction < 1.0;
+        let check_script = !self.allowed_scripts.is_empty() && self.min_script_fraction > 0.0;
+        if !check_rtl && !check_script {
+            return Some(text);
+        }
+
+        // count directly instead of using ScriptStats to avoid the script lookup and map if possible
+        let mut chars = 0;
+        let mut rtl_chars = 0;
+        let mut script_chars = 0;
+        for c in text.chars() {
+            chars += 1;
+            if check_rtl && !char_is_ltr(c) {
+                rtl_chars += 1;
+            }
+            if check_script && self.allowed_scripts.contains(&c.script()) {
+                script_chars += 1;
+            }
+        }
+
+        if check_rtl && fraction(rtl_chars, chars) > self.max_rtl_fraction {
+            return None;
+        }
+        if check_script && chars > 0 && fraction(script_chars, chars) < self.min_script_fraction {
+            return None;
+        }
+
+        Some(text)
+    }
+}
+
+fn strip_rtl_lines(text: &str) -> String {
+    text.split_inclusive('\n')
+        .filter(|line| str_is_ltr(line))
+        .collect()
+}
+
+fn fraction(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 / total as f64
+    }
+}
+
+/// Parse a script from its full (eg. "Latin") or short (eg. "Latn") name.
+pub fn parse_script(s: &str) -> Result<Script, String> {
+    Script::from_full_name(s)
+        .or_else(|| Script::from_short_name(s))
+        .ok_or_else(|| format!("Unknown script {:?}", s))
+}
+
+mod script_names {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use unicode_script::Script;
+
+    use crate::script::parse_script;
+
+    pub fn serialize<S: Serializer>(scripts: &[Script], serializer: S) -> Result<S::Ok, S::Error> {
+        let names = scripts.iter().map(|s| s.full_name()).collect::<Vec<_>>();
+        names.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Script>, D::Error> {
+        let names = Vec::<String>::deserialize(deserializer)?;
+        names
+            .iter()
+            .map(|s| parse_script(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use unicode_script::Script;
+
+    use crate::script::{ScriptFilter, ScriptStats};
+
+    #[test]
+    fn stray_rtl_char() {
+        let text = "fn main() {\n    // \u{5d0} is aleph\n    let x = 1;\n}\n".repeat(4);
+
+        assert_eq!(ScriptFilter::ltr_only().apply(text.clone()), None);
+
+        let lenient = ScriptFilter {
+            max_rtl_fraction: 0.05,
+            ..ScriptFilter::ltr_only()
+        };
+        assert_eq!(lenient.apply(text.clone()).as_ref(), Some(&text));
+
+        let strip = ScriptFilter {
+            strip_rtl_lines: true,
+            ..ScriptFilter::ltr_only()
+        };
+        let expected = "fn main() {\n    let x = 1;\n}\n".repeat(4);
+        assert_eq!(strip.apply(text), Some(expected));
+    }
+
+    #[test]
+    fn allowed_scripts() {
+        let filter = ScriptFilter {
+            allowed_scripts: vec![Script::Latin, Script::Common],
+            min_script_fraction: 0.9,
+            ..ScriptFilter::accept_all()
+        };
+
+        let latin = "Hello world, this is mostly Latin: \u{3b1}.";
+        let greek = "\u{3b1}\u{3b2}\u{3b3} \u{3b4}\u{3b5}";
+        assert!(filter.apply(latin.to_owned()).is_some());
+        assert!(filter.apply(greek.to_owned()).is_none());
+
+        let stats = ScriptStats::new(greek);
+        assert_eq!(stats.chars, 6);
+        assert_eq!(
+            stats.sorted_scripts(),
+            vec![(Script::Greek, 5), (Script::Common, 1)]
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::VecDeque;
+
+use aho_corasick::AhoCorasick;
+use ndarray::Array2;
+
+use crate::batch::{build_tokenizer, Stats};
+
+/// Deterministic batcher for evaluation that covers every token of every sample exactly once.
+///
+/// Each sample is split into windows of `seq_len` tokens that start `stride` tokens apart,
+/// so consecutive windows overlap by `seq_len - stride` tokens of context.
+/// The loss mask marks the tokens that are scored in each window, which are the ones not scored by a previous window.
+/// Rows are emitted in sample order, the rows of short samples and of the last batch are padded.
+pub struct SequentialBatcher {
+    // settings
+    batch_size: usize,
+    seq_len: usize,
+    stride: usize,
+    aho: AhoCorasick,
+
+    // state
+    stats: Stats,
+    rows: VecDeque<Row>,
+    tokens: Vec<usize>,
+}
+
+pub struct SequentialBatch {
+    /// The tokens, padded with -1.
+    pub tokens: Array2<i32>,
+    /// Whether each token should be scored, false for context tokens and padding.
+    pub loss_mask: Array2<bool>,
+    /// The sample index of each row, rows that are entirely padding repeat the last sample.
+    pub samples: Vec<usize>,
+    /// The index of the first token of each row within its sample.
+    pub start_indices: Vec<usize>,
+}
+
+struct Row {
+    sample: usize,
+    start_index: usize,
+    tokens: Vec<usize>,
+    // the number of context tokens at the start that are not scored
+    context: usize,
+}
+
+impl SequentialBatcher {
+    pub fn new<I, P>(batch_size: usize, seq_len: usize, stride: usize, tokens: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<[u8]>,
+    {
+        assert!(batch_size > 0, "Batch size cannot be zero");
+        assert!(seq_len > 0, "Sequence length cannot be zero");
+        assert!(
+            stride > 0 && stride <= seq_len,
+            "Stride must be in 1..=seq_len"
+        );
+
+        Self {
+            batch_size,
+            seq_len,
+            stride,
+            aho: build_tokenizer(tokens),
+            stats: Stats::default(),
+            rows: VecDeque::new(),
+            tokens: vec![],
+        }
+    }
+
+    pub fn push_sample(&mut self, sample: &str) -> bool {
+        self.tokens.clear();
+        self.tokens
+            .extend(self.aho.find_iter(sample).map(|m| m.pattern()));
+        if self.tokens.is_empty() {
+            return false;
+        }
+
+        let len = self.tokens.len();
+        let mut scored_end = 0;
+        let mut start = 0;
+        loop {
+            let end = (start + self.seq_len).min(len);
+            self.rows.push_back(Row {
+                sample: self.stats.sample_count,
+                start_index: start,
+                tokens: self.tokens[start..end].to_vec(),
+                context: scored_end - start,
+            });
+            scored_end = end;
+
+            if end == len {
+                break;
+            }
+            start += self.stride;
+        }
+
+        self.stats.sample_count += 1;
+        self.stats.token_count += len;
+        true
+    }
+
+    /// Pop a full batch if enough rows are available.
+    pub fn pop_batch(&mut self) -> Option<SequentialBatch> {
+        if self.rows.len() < self.batch_size {
+            return None;
+        }
+        Some(self.build_batch())
+    }
+
+    /// Pop the remaining rows as a final padded batch, to be called once all samples have been pushed.
+    pub fn pop_last_batch(&mut self) -> Option<SequentialBatch> {
+        if self.rows.is_empty() {
+            return None;
+        }
+        Some(self.build_batch())
+    }
+
+    fn build_batch(&mut self) -> SequentialBatch {
+        let mut tokens = Array2::from_elem((self.batch_size, self.seq_len), -1);
+        let mut loss_mask = Array2::from_elem((self.batch_size, self.seq_len), false);
+        let mut samples = vec![];
+        let mut start_indices = vec![];
+
+        for bi in 0..self.batch_size {
+            let row = match self.rows.pop_front() {
+                Some(row) => row,
+                None => {
+                    // padding row
+                    self.stats.padding_count += self.seq_len;
+                    samples.push(samples.last().copied().unwrap_or(0));
+                    start_indices.push(0);
+                    continue;
+                }
+            };
+
+            for (i, &token) in row.tokens.iter().enumerate() {
+                tokens[(bi, i)] = token as i32;
+                loss_mask[(bi, i)] = i >= row.context;
+            }
+            self.stats.padding_count += self.seq_len - row.tokens.len();
+            samples.push(row.sample);
+            start_indices.push(row.start_index);
+        }
+
+        self.stats.batch_count += 1;
+        SequentialBatch {
+            tokens,
+            loss_mask,
+            samples,
+            start_indices,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sequential::SequentialBatcher;
+
+    #[test]
+    fn covers_every_token_once() {
+        let tokens = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+
+        for stride in 1..=4 {
+            let mut batcher = SequentialBatcher::new(3, 4, stride, tokens);
+            let samples = ["abcdefghij", "ab", "abcde"];
+            for sample in samples {
+                batcher.push_sample(sample);
+            }
+
+            let mut scored = vec![vec![]; samples.len()];
+            let mut batches = vec![];
+            while let Some(batch) = batcher.pop_batch() {
+                batches.push(batch);
+            }
+            batches.extend(batcher.pop_last_batch());
+            assert!(batcher.pop_last_batch().is_none());
+
+            for batch in &batches {
+                for (bi, (&sample, &start)) in
+                    batch.samples.iter().zip(&batch.start_indices).enumerate()
+                {
+                    for i in 0..4 {
+                        if batch.loss_mask[(bi, i)] {
+                            assert_eq!(batch.tokens[(bi, i)], (start + i) as i32);
+                            scored[sample].push(start + i);
+                        }
+                    }
+                }
+            }
+
+            for (sample, scored) in samples.iter().zip(scored) {
+                assert_eq!(
+                    scored,
+                    (0..sample.len()).collect::<Vec<_>>(),
+                    "stride {}",
+                    stride
+                );
+            }
+        }
+    }
+}
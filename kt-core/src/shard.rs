@@ -0,0 +1,230 @@
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use zstd::Encoder;
+
+use crate::sample::Sample;
+
+/// Settings for writing samples to zstd compressed JSONL shards.
+#[derive(Debug, Clone, PartialEq, clap::Args, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShardConfig {
+    /// The uncompressed size in MB after which a new shard is started.
+    #[clap(long, default_value_t = 1024)]
+    pub shard_size_mb: u64,
+    /// The zstd compression level.
+    #[clap(long, default_value_t = 3)]
+    pub level: i32,
+    /// Prefix for the shard file names, which are `{prefix}{index:05}.jsonl.zst`.
+    #[clap(long, default_value = "")]
+    pub prefix: String,
+}
+
+/// Writes JSONL lines to a sequence of deterministically named shards in a directory.
+pub struct ShardWriter {
+    dir: PathBuf,
+    config: ShardConfig,
+
+    current: Option<Encoder<'static, BufWriter<File>>>,
+    current_bytes: u64,
+    paths: Vec<PathBuf>,
+
+    samples: u64,
+    bytes: u64,
+    line: Vec<u8>,
+}
+
+impl Default for ShardConfig {
+    fn default() -> Self {
+        ShardConfig {
+            shard_size_mb: 1024,
+            level: 3,
+            prefix: String::new(),
+        }
+    }
+}
+
+impl ShardConfig {
+    pub fn shard_path(&self, dir: &Path, index: usize) -> PathBuf {
+        dir.join(format!("{}{:05}.jsonl.zst", self.prefix, index))
+    }
+
+    fn is_shard_name(&self, name: &str) -> bool {
+        let index = name
+            .strip_prefix(self.prefix.as_str())
+            .and_then(|rest| rest.strip_suffix(".jsonl.zst"));
+        index.is_some_and(|index| !index.is_empty() && index.bytes().all(|c| c.is_ascii_digit()))
+    }
+}
+
+impl ShardWriter {
+    /// Create a writer for the given directory, creating it if necessary.
+    /// Fails if the directory already contains shards with the same prefix,
+    /// they could be mistaken for part of the output if fewer shards are written this time.
+    pub fn new(dir: impl Into<PathBuf>, config: ShardConfig) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+            if name.is_some_and(|name| config.is_shard_name(name)) {
+                return Err(std::io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!(
+                        "Output directory {:?} already contains shard {:?}, remove the old shards first",
+                        dir, path
+                    ),
+                ));
+            }
+        }
+
+        Ok(ShardWriter {
+            dir,
+            config,
+            current: None,
+            current_bytes: 0,
+            paths: vec![],
+            samples: 0,
+            bytes: 0,
+            line: vec![],
+        })
+    }
+
+    pub fn config(&self) -> &ShardConfig {
+        &self.config
+    }
+
+    /// The number of samples written so far.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// The number of uncompressed bytes written so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The paths of the shards started so far.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Serialize `sample` and write it as a single line.
+    pub fn write(&mut self, sample: &Sample) -> std::io::Result<()> {
+        let mut line = std::mem::take(&mut self.line);
+        line.clear();
+        serde_json::to_writer(&mut line, sample)?;
+        let result = self.write_line(&line);
+        self.line = line;
+        result
+    }
+
+    /// Write a single JSON line, which should not contain the trailing newline.
+    pub fn write_line(&mut self, line: &[u8]) -> std::io::Result<()> {
+        let writer = match &mut self.current {
+            Some(writer) => writer,
+            None => {
+                let path = self.config.shard_path(&self.dir, self.paths.len());
+                let writer = Encoder::new(BufWriter::new(File::create(&path)?), self.config.level)?;
+                self.paths.push(path);
+                self.current.insert(writer)
+            }
+        };
+
+        writer.write_all(line)?;
+        writer.write_all(b"\n")?;
+
+        let len = line.len() as u64 + 1;
+        self.samples += 1;
+        self.bytes += len;
+        self.current_bytes += len;
+
+        if self.current_bytes >= self.config.shard_size_mb.saturating_mul(1024 * 1024) {
+            self.finish_shard()?;
+        }
+        Ok(())
+    }
+
+    /// Finish the current shard, the next line is written to a new shard.
+    /// Does nothing if the current shard is still empty.
+    pub fn finish_shard(&mut self) -> std::io::Result<()> {
+        if let Some(writer) = self.current.take() {
+            writer.finish()?.flush()?;
+        }
+        self.current_bytes = 0;
+        Ok(())
+    }
+
+    /// Finish the last shard and return the paths of all shards written.
+    pub fn finish(mut self) -> std::io::Result<Vec<PathBuf>> {
+        self.finish_shard()?;
+        Ok(self.paths)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+
+    use crate::normalize::Normalization;
+    use crate::pipeline::Pipeline;
+    use crate::sample::{Meta, Sample, SampleReader};
+    use crate::shard::{ShardConfig, ShardWriter};
+
+    #[test]
+    fn roundtrip() {
+        let dir = std::env::temp_dir().join(format!("kt-shard-test-{}", std::process::id()));
+        let config = ShardConfig {
+            shard_size_mb: 1,
+            ..ShardConfig::default()
+        };
+
+        let mut meta = Meta {
+            pile_set_name: "Test".to_owned(),
+            extra: Default::default(),
+        };
+        meta.extra.insert("id".to_owned(), 5.into());
+        let sample = Sample {
+            text: "x".repeat(400 * 1024),
+            meta,
+        };
+
+        let mut writer = ShardWriter::new(&dir, config).unwrap();
+        for _ in 0..5 {
+            writer.write(&sample).unwrap();
+        }
+        let paths = writer.finish().unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[1].ends_with("00001.jsonl.zst"));
+
+        let mut count = 0;
+        for path in &paths {
+            let file = File::open(path).unwrap();
+            for read in
+                SampleReader::new_decode(file, Pipeline::empty(), Normalization::NONE).unwrap()
+            {
+                let read = read.unwrap();
+                assert_eq!(read.text, sample.text);
+                assert_eq!(read.meta.extra["id"], 5);
+                count += 1;
+            }
+        }
+        assert_eq!(count, 5);
+
+        // the old shards could be mistaken for part of a new, smaller output
+        let err = ShardWriter::new(&dir, ShardConfig::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        let other_prefix = ShardConfig {
+            prefix: "other-".to_owned(),
+            ..ShardConfig::default()
+        };
+        assert!(ShardWriter::new(&dir, other_prefix).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use zstd::{Decoder, Encoder};
+
+use crate::error::invalid_input;
+use crate::shard::ShardWriter;
+
+/// Settings for globally shuffling a corpus that does not fit in memory.
+#[derive(Debug, Clone, PartialEq, clap::Args, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShuffleConfig {
+    /// The seed of the random permutation.
+    #[clap(long, default_value_t = 0)]
+    pub seed: u64,
+    /// The uncompressed size in MB of the samples that are loaded into memory at once.
+    #[clap(long, default_value_t = 1024)]
+    pub memory_mb: u64,
+    /// The number of temporary bucket files, estimated from the input sizes and `memory_mb` by default.
+    #[clap(long)]
+    pub buckets: Option<usize>,
+}
+
+/// All bucket files are open for writing at the same time, each using a file descriptor and a zstd encoder.
+pub const MAX_BUCKETS: usize = 512;
+
+/// The assumed zstd compression ratio of the inputs, only used to estimate the bucket count.
+const COMPRESSION_RATIO_ESTIMATE: u64 = 4;
+
+#[derive(Debug, Clone, Default)]
+pub struct ShuffleStats {
+    pub samples: u64,
+    pub bytes: u64,
+    pub buckets: usize,
+    /// The uncompressed size of the largest bucket, this is the peak memory usage.
+    pub max_bucket_bytes: u64,
+}
+
+impl Default for ShuffleConfig {
+    fn default() -> Self {
+        ShuffleConfig {
+            seed: 0,
+            memory_mb: 1024,
+            buckets: None,
+        }
+    }
+}
+
+impl ShuffleConfig {
+    fn bucket_count(&self, inputs: &[PathBuf]) -> std::io::Result<usize> {
+        let buckets = match self.buckets {
+            Some(buckets) => buckets.max(1),
+            None => {
+                let mut compressed = 0;
+                for input in inputs {
+                    compressed += std::fs::metadata(input)?.len();
+                }
+                let memory = self.memory_mb.max(1) * 1024 * 1024;
+                (compressed * COMPRESSION_RATIO_ESTIMATE)
+                    .div_ceil(memory)
+                    .max(1) as usize
+            }
+        };
+
+        if buckets > MAX_BUCKETS {
+            return Err(invalid_input(format!(
+                "Shuffling needs {} buckets but at most {} can be open at once, increase --memory-mb or lower --buckets",
+                buckets, MAX_BUCKETS
+            )));
+        }
+        Ok(buckets)
+    }
+}
+
+/// Globally shuffle the lines of all zstd JSONL `inputs` into `writer`, using two passes.
+/// First every line is scattered to a random temporary bucket file in `temp_dir`,
+/// then the buckets are loaded one at a time, shuffled in memory and appended to the output.
+///
+/// If `output_shards` is set the samples are split evenly over that many shards,
+/// otherwise the shard size of `writer` is used.
+pub fn shuffle(
+    inputs: &[PathBuf],
+    temp_dir: &Path,
+    writer: &mut ShardWriter,
+    output_shards: Option<usize>,
+    config: &ShuffleConfig,
+) -> std::io::Result<ShuffleStats> {
+    let mut rng = SmallRng::seed_from_u64(config.seed);
+    let mut stats = ShuffleStats {
+        buckets: config.bucket_count(inputs)?,
+        ..ShuffleStats::default()
+    };
+
+    // scatter
+    std::fs::create_dir_all(temp_dir)?;
+    let bucket_paths = (0..stats.buckets)
+        .map(|i| temp_dir.join(format!("bucket-{:05}.jsonl.zst", i)))
+        .collect::<Vec<_>>();
+    let mut bucket_writers = bucket_paths
+        .iter()
+        .map(|path| Encoder::new(BufWriter::new(File::create(path)?), 1))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut bucket_bytes = vec![0; stats.buckets];
+
+    for input in inputs {
+        let reader = BufReader::new(Decoder::new(File::open(input)?)?);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let bucket = rng.gen_range(0..stats.buckets);
+            bucket_writers[bucket].write_all(line.as_bytes())?;
+            bucket_writers[bucket].write_all(b"\n")?;
+
+            bucket_bytes[bucket] += line.len() as u64 + 1;
+            stats.samples += 1;
+        }
+    }
+    for bucket_writer in bucket_writers {
+        bucket_writer.finish()?.flush()?;
+    }
+
+    stats.bytes = bucket_bytes.iter().sum();
+    stats.max_bucket_bytes = bucket_bytes.iter().copied().max().unwrap_or(0);
+
+    // gather
+    let mut written = 0;
+    for path in &bucket_paths {
+        let reader = BufReader::new(Decoder::new(File::open(path)?)?);
+        let mut lines = reader.lines().collect::<std::io::Result<Vec<_>>>()?;
+        lines.shuffle(&mut rng);
+
+        for line in &lines {
+            if let Some(shards) = output_shards {
+                let shards = shards as u64;
+                if written > 0
+                    && written * shards / stats.samples != (written - 1) * shards / stats.samples
+                {
+                    writer.finish_shard()?;
+                }
+            }
+
+            writer.write_line(line.as_bytes())?;
+            written += 1;
+        }
+
+        std::fs::remove_file(path)?;
+    }
+
+    // only removes the directory if nothing else was in it
+    let _ = std::fs::remove_dir(temp_dir);
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::path::Path;
+
+    use crate::normalize::Normalization;
+    use crate::pipeline::Pipeline;
+    use crate::sample::{Meta, Sample, SampleReader};
+    use crate::shard::{ShardConfig, ShardWriter};
+    use crate::shuffle::{shuffle, ShuffleConfig, MAX_BUCKETS};
+
+    fn run(dir: &Path, output: &str, seed: u64) -> Vec<Vec<String>> {
+        let config = ShuffleConfig {
+            seed,
+            buckets: Some(4),
+            ..ShuffleConfig::default()
+        };
+        let inputs = [
+            dir.join("input/00000.jsonl.zst"),
+            dir.join("input/00001.jsonl.zst"),
+        ];
+
+        let mut writer = ShardWriter::new(dir.join(output), ShardConfig::default()).unwrap();
+        let stats = shuffle(&inputs, &dir.join("tmp"), &mut writer, Some(3), &config).unwrap();
+        assert_eq!(stats.samples, 1000);
+
+        let paths = writer.finish().unwrap();
+        paths
+            .iter()
+            .map(|path| {
+                let file = File::open(path).unwrap();
+                SampleReader::new_decode(file, Pipeline::empty(), Normalization::NONE)
+                    .unwrap()
+                    .map(|s| s.unwrap().text)
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn permutation() {
+        let dir = std::env::temp_dir().join(format!("kt-shuffle-test-{}", std::process::id()));
+
+        let mut writer = ShardWriter::new(dir.join("input"), ShardConfig::default()).unwrap();
+        for i in 0..1000 {
+            if i == 500 {
+                writer.finish_shard().unwrap();
+            }
+            let meta = Meta {
+                pile_set_name: "Test".to_owned(),
+                extra: Default::default(),
+            };
+            writer
+                .write(&Sample {
+                    text: format!("sample {}", i),
+                    meta,
+                })
+                .unwrap();
+        }
+        assert_eq!(writer.finish().unwrap().len(), 2);
+
+        let shards = run(&dir, "a", 0);
+        assert_eq!(
+            shards.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            vec![334, 333, 333]
+        );
+
+        let mut all = shards.concat();
+        let expected = (0..1000)
+            .map(|i| format!("sample {}", i))
+            .collect::<Vec<_>>();
+        assert_ne!(all, expected);
+        all.sort_by_key(|s| s[7..].parse::<u32>().unwrap());
+        assert_eq!(all, expected);
+
+        assert_eq!(run(&dir, "b", 0), shards);
+        assert_ne!(run(&dir, "c", 1), shards);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn too_many_buckets() {
+        let config = ShuffleConfig {
+            buckets: Some(MAX_BUCKETS + 1),
+            ..ShuffleConfig::default()
+        };
+        let err = config.bucket_count(&[]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
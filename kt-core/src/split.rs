@@ -0,0 +1,309 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::invalid_input;
+use crate::hash::{mix64, stable_hash};
+use crate::sample::Sample;
+
+/// Settings for assigning each sample to a split, eg. train and validation, based on a stable hash.
+/// The assignment only depends on the hashed text or key, so it is the same for every reader and every run.
+#[derive(Debug, Clone, PartialEq, clap::Args, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SplitConfig {
+    /// The split names and their fractions, the fractions are normalized to sum to one.
+    #[clap(
+        long = "splits",
+        value_delimiter = ',',
+        default_value = "train=0.99,val=0.01"
+    )]
+    pub splits: Vec<SplitFraction>,
+    /// Different fractions for a single `pile_set_name`, eg. "Github:train=0.999,val=0.001".
+    /// Can be repeated, the split names must be a subset of the names in `splits`.
+    #[clap(long = "set-splits")]
+    pub set_splits: Vec<SetSplits>,
+    /// Hash this metadata field instead of the text, samples that don't have it are hashed by text.
+    #[clap(long = "split-key")]
+    pub key: Option<String>,
+    /// Seed mixed into the hash, to get a different but still stable assignment.
+    #[clap(long = "split-seed", default_value_t = 0)]
+    pub seed: u64,
+}
+
+/// A split name and its fraction, written as `name=fraction`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SplitFraction {
+    pub name: String,
+    pub fraction: f64,
+}
+
+/// The split fractions for a single set, written as `set:name=fraction,name=fraction`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SetSplits {
+    pub set: String,
+    pub splits: Vec<SplitFraction>,
+}
+
+/// Assigns samples to splits, built from a [SplitConfig].
+#[derive(Debug, Clone)]
+pub struct Splitter {
+    names: Vec<String>,
+    key: Option<String>,
+    seed: u64,
+    // cumulative upper bounds for each split index
+    default_bounds: Vec<f64>,
+    set_bounds: Vec<(String, Vec<f64>)>,
+}
+
+impl Default for SplitConfig {
+    fn default() -> Self {
+        SplitConfig {
+            splits: vec![
+                SplitFraction::new("train", 0.99),
+                SplitFraction::new("val", 0.01),
+            ],
+            set_splits: vec![],
+            key: None,
+            seed: 0,
+        }
+    }
+}
+
+impl SplitFraction {
+    pub fn new(name: &str, fraction: f64) -> Self {
+        SplitFraction {
+            name: name.to_owned(),
+            fraction,
+        }
+    }
+}
+
+impl Splitter {
+    pub fn new(config: &SplitConfig) -> std::io::Result<Self> {
+        let names: Vec<String> = config.splits.iter().map(|s| s.name.clone()).collect();
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) {
+                return Err(invalid_input(format!("Duplicate split {:?}", name)));
+            }
+        }
+
+        let default_bounds = bounds(&names, &config.splits)?;
+        let set_bounds = config
+            .set_splits
+            .iter()
+            .map(|s| Ok((s.set.clone(), bounds(&names, &s.splits)?)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok(Splitter {
+            names,
+            key: config.key.clone(),
+            seed: config.seed,
+            default_bounds,
+            set_bounds,
+        })
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The index of the split with the given name.
+    pub fn index(&self, name: &str) -> std::io::Result<usize> {
+        self.names.iter().position(|n| n == name).ok_or_else(|| {
+            invalid_input(format!(
+                "Unknown split {:?}, expected one of {:?}",
+                name, self.names
+            ))
+        })
+    }
+
+    /// The index of the split `sample` belongs to.
+    pub fn split_of(&self, sample: &Sample) -> usize {
+        let hash = match self.key.as_ref().and_then(|k| sample.meta.extra.get(k)) {
+            Some(Value::String(key)) => stable_hash(key.as_bytes()),
+            Some(key) => stable_hash(key.to_string().as_bytes()),
+            None => stable_hash(sample.text.as_bytes()),
+        };
+        // uniform in [0, 1) using the top 53 bits
+        let x = (mix64(hash ^ mix64(self.seed)) >> 11) as f64 / (1u64 << 53) as f64;
+
+        let bounds = self
+            .set_bounds
+            .iter()
+            .find(|(set, _)| *set == sample.meta.pile_set_name)
+            .map_or(&self.default_bounds, |(_, bounds)| bounds);
+        bounds
+            .iter()
+            .position(|&b| x < b)
+            .unwrap_or(self.names.len() - 1)
+    }
+}
+
+/// The cumulative normalized fractions of `splits`, in the order of `names`.
+fn bounds(names: &[String], splits: &[SplitFraction]) -> std::io::Result<Vec<f64>> {
+    let mut fractions = vec![0.0; names.len()];
+    for split in splits {
+        let index = names
+            .iter()
+            .position(|n| *n == split.name)
+            .ok_or_else(|| invalid_input(format!("Unknown split {:?}", split.name)))?;
+        if !(split.fraction >= 0.0 && split.fraction.is_finite()) {
+            return Err(invalid_input(format!(
+                "Invalid fraction for split {:?}",
+                split.name
+            )));
+        }
+        fractions[index] += split.fraction;
+    }
+
+    let total: f64 = fractions.iter().sum();
+    if total <= 0.0 {
+        return Err(invalid_input(
+            "Split fractions must sum to a positive value".to_owned(),
+        ));
+    }
+
+    let mut cumulative = 0.0;
+    Ok(fractions
+        .iter()
+        .map(|f| {
+            cumulative += f / total;
+            cumulative
+        })
+        .collect())
+}
+
+impl FromStr for SplitFraction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, fraction) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected name=fraction, got {:?}", s))?;
+        let fraction = fraction
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid fraction in {:?}: {}", s, e))?;
+        Ok(SplitFraction {
+            name: name.trim().to_owned(),
+            fraction,
+        })
+    }
+}
+
+impl Display for SplitFraction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.fraction)
+    }
+}
+
+impl FromStr for SetSplits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (set, splits) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Expected set:name=fraction,..., got {:?}", s))?;
+        let splits = splits
+            .split(',')
+            .map(SplitFraction::from_str)
+            .collect::<Result<_, _>>()?;
+        Ok(SetSplits {
+            set: set.to_owned(),
+            splits,
+        })
+    }
+}
+
+impl Display for SetSplits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.set)?;
+        for (i, split) in self.splits.iter().enumerate() {
+            if i != 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", split)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for SplitFraction {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SplitFraction> for String {
+    fn from(value: SplitFraction) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for SetSplits {
+    type Error = String;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<SetSplits> for String {
+    fn from(value: SetSplits) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sample::{Meta, Sample};
+    use crate::split::{SplitConfig, Splitter};
+
+    fn sample(text: String, set: &str) -> Sample {
+        Sample {
+            text,
+            meta: Meta {
+                pile_set_name: set.to_owned(),
+                extra: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn fractions() {
+        let config: SplitConfig = serde_json::from_str(
+            r#"{"splits": ["train=0.8", "val=0.2"], "set_splits": ["Github:train=1"]}"#,
+        )
+        .unwrap();
+        let splitter = Splitter::new(&config).unwrap();
+        assert_eq!(splitter.index("val").unwrap(), 1);
+        assert!(splitter.index("test").is_err());
+
+        let mut counts = [0; 2];
+        for i in 0..10_000 {
+            let text = format!("sample {}", i);
+            let split = splitter.split_of(&sample(text.clone(), "Pile-CC"));
+            counts[split] += 1;
+
+            // stable across calls and splitters
+            assert_eq!(
+                split,
+                Splitter::new(&config)
+                    .unwrap()
+                    .split_of(&sample(text.clone(), "Pile-CC"))
+            );
+            assert_eq!(splitter.split_of(&sample(text, "Github")), 0);
+        }
+        assert!((1800..2200).contains(&counts[1]), "{:?}", counts);
+
+        let bad = SplitConfig {
+            set_splits: vec!["Github:test=1".parse().unwrap()],
+            ..SplitConfig::default()
+        };
+        assert!(Splitter::new(&bad).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::normalize::Normalization;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Vocab {
+    pub tokens: Vec<Vec<u8>>,
+    // vocabs from before this field existed were always trained on NFC text
+    #[serde(default)]
+    pub normalization: Normalization,
+
+    // other fields (eg. the args used to generate this vocab) are kept as-is
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Vocab {
+    pub fn new(tokens: Vec<Vec<u8>>, normalization: Normalization) -> Self {
+        Vocab {
+            tokens,
+            normalization,
+            extra: Map::default(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
@@ -1,22 +1,115 @@
-from typing import List, Optional
+from os import PathLike
+from typing import Any, Awaitable, Dict, List, Optional, Tuple, Union
 
 import numpy as np
 
 
+# either the path of a vocab file or a list of tokens,
+# a list of tokens uses NFC normalization unless `normalization` is passed
+VocabSource = Union[str, PathLike, List[List[int]]]
+
+
+# raised by the readers when reading samples fails
+class ReadError(OSError):
+    path: Optional[str]
+    # 1-based, None if the file could not be opened
+    line: Optional[int]
+    # offset of the start of the line in the decompressed data
+    offset: int
+    # "open", "io", "utf8" or "json"
+    kind: str
+
+
+# a line is not valid UTF-8 or not a valid JSON sample
+class InvalidSampleError(ReadError): ...
+
+
 class Tokenizer:
-    def __init__(self, tokens: List[List[int]]): ...
+    def __init__(self, vocab: VocabSource, normalization: Optional[str] = None): ...
+
+    @property
+    def normalization(self) -> str: ...
 
     def tokenize(self, s: str) -> np.ndarray: ...
 
+    def tokenize_with_offsets(
+            self, s: str, char_offsets: bool = True,
+    ) -> Tuple[np.ndarray, np.ndarray, np.ndarray]: ...
+
+    def tokenize_batch(
+            self, texts: List[str],
+            padded: bool = True, num_threads: Optional[int] = None,
+    ) -> Tuple[np.ndarray, np.ndarray]: ...
+
 
 class BatchTokenReader:
     def __init__(
             self,
-            tokens: List[List[int]], data_paths: List[str],
+            vocab: VocabSource, data_paths: List[str],
             batch_size: int, seq_len: int,
             bucket_count: int, queue_size: int,
+            normalization: Optional[str] = None,
+            pipeline: Optional[str] = None,
+            split: Optional[str] = None,
+            split_config: Optional[str] = None,
+            keep_short_tails: bool = False,
+            pad_multiple: Optional[int] = None,
+            max_tokens: Optional[int] = None,
+            # (batch, seq_len, batch_size) steps
+            schedule: Optional[List[Tuple[int, int, int]]] = None,
+            start_batch: int = 0,
+            # seconds to wait for a batch before raising TimeoutError
+            timeout: Optional[float] = None,
+            # "int32", "uint16" or "uint32"
+            dtype: str = "int32",
     ): ...
 
     def __iter__(self) -> BatchTokenReader: ...
 
-    def __next__(self) -> Optional[np.array]: ...
+    # tokens, or (tokens, lengths) if pad_multiple is set
+    def __next__(self) -> Optional[Union[np.array, Tuple[np.array, np.array]]]: ...
+
+    def __aiter__(self) -> BatchTokenReader: ...
+
+    def __anext__(self) -> Awaitable[Union[np.array, Tuple[np.array, np.array]]]: ...
+
+    # stops the reader thread and waits for it to finish, iteration waiting in another thread stops
+    def close(self) -> None: ...
+
+    def __enter__(self) -> BatchTokenReader: ...
+
+    def __exit__(self, exc_type, exc_value, traceback) -> bool: ...
+
+    # counters, throughput and per-stage pipeline counts
+    def stats(self) -> Dict[str, Any]: ...
+
+
+class EvalTokenReader:
+    def __init__(
+            self,
+            vocab: VocabSource, data_paths: List[str],
+            batch_size: int, seq_len: int,
+            stride: Optional[int] = None,
+            queue_size: int = 16,
+            normalization: Optional[str] = None,
+            pipeline: Optional[str] = None,
+            split: Optional[str] = None,
+            split_config: Optional[str] = None,
+            timeout: Optional[float] = None,
+    ): ...
+
+    def __iter__(self) -> EvalTokenReader: ...
+
+    # (tokens, loss_mask, samples, start_indices)
+    def __next__(self) -> Optional[Tuple[np.array, np.array, np.array, np.array]]: ...
+
+    def __aiter__(self) -> EvalTokenReader: ...
+
+    def __anext__(self) -> Awaitable[Tuple[np.array, np.array, np.array, np.array]]: ...
+
+    # stops the reader thread and waits for it to finish, iteration waiting in another thread stops
+    def close(self) -> None: ...
+
+    def __enter__(self) -> EvalTokenReader: ...
+
+    def __exit__(self, exc_type, exc_value, traceback) -> bool: ...
@@ -1,68 +1,452 @@
 use std::fs::File;
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
 use aho_corasick::AhoCorasick;
-use flume::{Receiver, RecvError, SendError, Sender};
+use flume::{Receiver, RecvTimeoutError, SendError, Sender};
 use itertools::Itertools;
+use ndarray::{Array1, Array2};
 use numpy::IntoPyArray;
 use numpy::{PyArray1, PyArray2};
+use pyo3::exceptions::{PyRuntimeError, PyStopAsyncIteration, PyTimeoutError, PyValueError};
 use pyo3::prelude::*;
-use unicode_normalization::UnicodeNormalization;
+use pyo3::types::PyDict;
 
-use kt_core::batch::{build_tokenizer, Batch, Batcher};
-use kt_core::sample::SampleReader;
+use kt_core::batch::{build_tokenizer, Batch, BatchShape, Batcher, Stats, TokenId};
+use kt_core::error;
+use kt_core::normalize::Normalization;
+use kt_core::pipeline::{Pipeline, StageCounts};
+use kt_core::sample::{DecodeSampleReader, SampleReader};
+use kt_core::schedule::{Schedule, ScheduleStep};
+use kt_core::sequential::{SequentialBatch, SequentialBatcher};
+use kt_core::split::{SplitConfig, Splitter};
+use kt_core::vocab::Vocab;
+
+use crate::exceptions::{InvalidSampleError, ReadError};
 
 #[pymodule]
-fn ktoken(_py: Python, m: &PyModule) -> PyResult<()> {
+fn ktoken(py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<Tokenizer>()?;
     m.add_class::<BatchTokenReader>()?;
+    m.add_class::<EvalTokenReader>()?;
+    m.add("ReadError", py.get_type::<ReadError>())?;
+    m.add("InvalidSampleError", py.get_type::<InvalidSampleError>())?;
     Ok(())
 }
 
+mod exceptions {
+    // the macro of this pyo3 version checks a cfg that newer compilers don't know about
+    #![allow(unexpected_cfgs)]
+
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyOSError;
+
+    create_exception!(
+        ktoken,
+        ReadError,
+        PyOSError,
+        "Reading samples failed, the `path`, `line`, `offset` and `kind` attributes tell where and why."
+    );
+    create_exception!(
+        ktoken,
+        InvalidSampleError,
+        ReadError,
+        "A line of the input is not valid UTF-8 or not a valid JSON sample."
+    );
+}
+
+/// Convert an error from a reader thread, raising `ReadError` with the location if it has one.
+fn read_error_to_py(py: Python, err: std::io::Error) -> PyErr {
+    let read_error = match error::ReadError::find(&err) {
+        Some(read_error) => read_error,
+        None => return err.into(),
+    };
+
+    let py_err = if read_error.is_invalid_data() {
+        InvalidSampleError::new_err(read_error.to_string())
+    } else {
+        ReadError::new_err(read_error.to_string())
+    };
+
+    let value = py_err.value(py);
+    let attributes = [
+        ("path", read_error.path.clone().into_py(py)),
+        ("line", read_error.line.into_py(py)),
+        ("offset", read_error.offset.into_py(py)),
+        ("kind", read_error.kind.name().into_py(py)),
+    ];
+    for (name, attribute) in attributes {
+        if let Err(err) = value.setattr(name, attribute) {
+            return err;
+        }
+    }
+    py_err
+}
+
 #[pyclass]
 struct BatchTokenReader {
-    receiver: Receiver<Message>,
+    handle: ReaderHandle<AnyBatch>,
+    shape: BatchShape,
+    queue_size: usize,
+    // gives the buffers of batches python no longer uses back to the reader thread
+    recycle: Sender<AnyBatch>,
+    // the index of the first batch, `start_batch` if there is a schedule
+    first_batch_index: usize,
+    start: Instant,
+    stats: Arc<Mutex<ReaderStats>>,
 }
 
+/// A batch of one of the supported token types.
+enum AnyBatch {
+    I32(Batch<i32>),
+    U16(Batch<u16>),
+    U32(Batch<u32>),
+}
+
+/// A token type that can be passed to python.
+trait PyToken: TokenId + numpy::Element {
+    const DTYPE: &'static str;
+
+    fn wrap(batch: Batch<Self>) -> AnyBatch;
+    fn unwrap(batch: AnyBatch) -> Option<Batch<Self>>;
+    fn unwrap_ref(batch: &AnyBatch) -> Option<&Batch<Self>>;
+}
+
+macro_rules! impl_py_token {
+    ($ty: ty, $variant: ident, $dtype: literal) => {
+        impl PyToken for $ty {
+            const DTYPE: &'static str = $dtype;
+
+            fn wrap(batch: Batch<Self>) -> AnyBatch {
+                AnyBatch::$variant(batch)
+            }
+
+            fn unwrap(batch: AnyBatch) -> Option<Batch<Self>> {
+                match batch {
+                    AnyBatch::$variant(batch) => Some(batch),
+                    _ => None,
+                }
+            }
+
+            fn unwrap_ref(batch: &AnyBatch) -> Option<&Batch<Self>> {
+                match batch {
+                    AnyBatch::$variant(batch) => Some(batch),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl_py_token!(i32, I32, "int32");
+impl_py_token!(u16, U16, "uint16");
+impl_py_token!(u32, U32, "uint32");
+
+/// Owns the memory of a token array passed to numpy,
+/// and gives it back to the reader thread once numpy no longer references it.
+#[pyclass]
+struct BatchBuffer {
+    // only `None` while dropping
+    batch: Option<AnyBatch>,
+    recycle: Sender<AnyBatch>,
+}
+
+impl Drop for BatchBuffer {
+    fn drop(&mut self) {
+        if let Some(batch) = self.batch.take() {
+            // if the reader thread already has enough buffers or is closed this one is freed
+            let _ = self.recycle.try_send(batch);
+        }
+    }
+}
+
+/// Counters of the reader thread, shared with the python side.
+#[derive(Debug, Default, Clone)]
+struct ReaderStats {
+    batcher: Stats,
+    seq_len: usize,
+    batch_size: usize,
+    bytes_read: u64,
+    pipeline: Vec<(&'static str, StageCounts)>,
+
+    // time spent reading, filtering and normalizing samples
+    decode_time: Duration,
+    tokenize_time: Duration,
+    batch_time: Duration,
+}
+
+#[pyclass]
+struct EvalTokenReader {
+    handle: ReaderHandle<SequentialBatch>,
+}
+
+/// The receiving side of a reader thread.
+///
+/// Everything is behind shared references, so `close` can be called while another thread is waiting in `recv`.
+struct ReaderHandle<B> {
+    // `None` once closed
+    receiver: Mutex<Option<Receiver<Message<B>>>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+    timeout: Option<Duration>,
+    // the number of batches returned to python
+    received: AtomicUsize,
+}
+
+/// How often a blocked receive checks for signals, eg. Ctrl-C.
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
 #[pyclass]
 struct Tokenizer {
     aho: AhoCorasick,
+    normalization: Normalization,
+}
+
+/// Either the path of a vocab file or the token list itself.
+#[derive(FromPyObject)]
+enum VocabSource {
+    Path(PathBuf),
+    Tokens(Vec<Vec<u8>>),
+}
+
+impl VocabSource {
+    /// Get the tokens and normalization, checking that `normalization` matches the vocab file if both are given.
+    /// A plain token list does not record its normalization, it defaults to NFC.
+    fn resolve(self, normalization: Option<&str>) -> PyResult<(Vec<Vec<u8>>, Normalization)> {
+        let normalization = normalization
+            .map(|s| s.parse::<Normalization>().map_err(PyValueError::new_err))
+            .transpose()?;
+
+        match self {
+            VocabSource::Path(path) => {
+                let vocab = Vocab::load(&path)?;
+                if let Some(normalization) = normalization {
+                    if normalization != vocab.normalization {
+                        return Err(PyValueError::new_err(format!(
+                            "Normalization {} does not match normalization {} of vocab {:?}",
+                            normalization, vocab.normalization, path
+                        )));
+                    }
+                }
+                Ok((vocab.tokens, vocab.normalization))
+            }
+            VocabSource::Tokens(tokens) => Ok((tokens, normalization.unwrap_or_default())),
+        }
+    }
 }
 
 #[pymethods]
 impl Tokenizer {
+    /// `vocab` is either the path of a vocab file or a list of tokens.
+    /// The normalization defaults to the one stored in the vocab file, or NFC for a list of tokens.
     #[new]
-    fn new(tokens: Vec<Vec<u8>>) -> Self {
-        Tokenizer {
+    #[args(normalization = "None")]
+    fn new(vocab: VocabSource, normalization: Option<&str>) -> PyResult<Self> {
+        let (tokens, normalization) = vocab.resolve(normalization)?;
+        Ok(Tokenizer {
             aho: build_tokenizer(&tokens),
-        }
+            normalization,
+        })
+    }
+
+    #[getter]
+    fn normalization(&self) -> String {
+        self.normalization.to_string()
     }
 
     fn tokenize<'py>(&self, py: Python<'py>, s: &str) -> &'py PyArray1<i32> {
-        // unicode normalization
-        let s_norm = s.nfc().collect::<String>();
+        tokenize_str(&self.aho, self.normalization, s).into_pyarray(py)
+    }
 
-        // actual tokenization
-        self.aho
-            .find_iter(&s_norm)
-            .map(|m| m.pattern() as i32)
-            .collect_vec()
-            .into_pyarray(py)
+    /// Tokenize a string, also returning the span of each token in the original (un-normalized) string.
+    ///
+    /// Returns `(tokens, starts, ends)`, with the offsets in characters if `char_offsets`, otherwise in UTF-8 bytes.
+    /// Spans of tokens that only cover part of a character or of a sequence changed by normalization are rounded outwards.
+    #[args(char_offsets = "true")]
+    fn tokenize_with_offsets<'py>(
+        &self,
+        py: Python<'py>,
+        s: &str,
+        char_offsets: bool,
+    ) -> (&'py PyArray1<i32>, &'py PyArray1<i64>, &'py PyArray1<i64>) {
+        let (s_norm, map) = self.normalization.apply_with_offsets(s);
+
+        // for each byte offset, the number of chars that start before it
+        let chars_before = if char_offsets {
+            let mut chars_before = Vec::with_capacity(s.len() + 1);
+            let mut count = 0;
+            for i in 0..=s.len() {
+                chars_before.push(count);
+                if s.is_char_boundary(i) {
+                    count += 1;
+                }
+            }
+            chars_before
+        } else {
+            vec![]
+        };
+
+        let mut tokens = vec![];
+        let mut starts = vec![];
+        let mut ends = vec![];
+
+        for m in self.aho.find_iter(&s_norm) {
+            let (start, end) = map.map_range(m.start(), m.end());
+            let (start, end) = if char_offsets {
+                // round outwards to whole chars
+                (chars_before[start + 1] - 1, chars_before[end])
+            } else {
+                (start, end)
+            };
+
+            tokens.push(m.pattern() as i32);
+            starts.push(start as i64);
+            ends.push(end as i64);
+        }
+
+        (
+            tokens.into_pyarray(py),
+            starts.into_pyarray(py),
+            ends.into_pyarray(py),
+        )
+    }
+
+    /// Tokenize multiple strings in parallel without holding the GIL.
+    ///
+    /// Returns `(tokens, lengths)` with `tokens` padded with -1 if `padded`,
+    /// otherwise the flat concatenated `(tokens, offsets)` with `offsets` of length `len(texts) + 1`.
+    #[args(padded = "true", num_threads = "None")]
+    fn tokenize_batch(
+        &self,
+        py: Python,
+        texts: Vec<String>,
+        padded: bool,
+        num_threads: Option<usize>,
+    ) -> PyResult<PyObject> {
+        let num_threads = match num_threads {
+            Some(num_threads) => num_threads,
+            None => std::thread::available_parallelism()?.get(),
+        };
+        if num_threads == 0 {
+            return Err(PyValueError::new_err("num_threads cannot be zero"));
+        }
+
+        let aho = &self.aho;
+        let normalization = self.normalization;
+        let result = py.allow_threads(|| {
+            let tokenized = tokenize_parallel(aho, normalization, &texts, num_threads);
+
+            if padded {
+                let max_len = tokenized.iter().map(|t| t.len()).max().unwrap_or(0);
+                let mut tokens = Array2::from_elem((texts.len(), max_len), -1);
+                for (i, t) in tokenized.iter().enumerate() {
+                    for (j, &token) in t.iter().enumerate() {
+                        tokens[(i, j)] = token;
+                    }
+                }
+                let lengths = tokenized.iter().map(|t| t.len() as i64).collect();
+                (tokens.into_dyn(), Array1::from_vec(lengths))
+            } else {
+                let mut offsets = vec![0];
+                offsets.extend(tokenized.iter().scan(0, |acc, t| {
+                    *acc += t.len() as i64;
+                    Some(*acc)
+                }));
+                let tokens = tokenized.concat();
+                (
+                    Array1::from_vec(tokens).into_dyn(),
+                    Array1::from_vec(offsets),
+                )
+            }
+        });
+
+        let (tokens, lengths_or_offsets) = result;
+        Ok((tokens.into_pyarray(py), lengths_or_offsets.into_pyarray(py)).into_py(py))
     }
 }
 
-#[pymethods]
-impl BatchTokenReader {
-    #[new]
+/// Normalize and tokenize a single string.
+fn tokenize_str(aho: &AhoCorasick, normalization: Normalization, s: &str) -> Vec<i32> {
+    // unicode normalization
+    let s_norm = normalization.apply(s);
+
+    // actual tokenization
+    aho.find_iter(&s_norm)
+        .map(|m| m.pattern() as i32)
+        .collect_vec()
+}
+
+/// Tokenize `texts` by splitting them into contiguous chunks, one per thread.
+fn tokenize_parallel(
+    aho: &AhoCorasick,
+    normalization: Normalization,
+    texts: &[String],
+    num_threads: usize,
+) -> Vec<Vec<i32>> {
+    let chunk_size = texts.len().div_ceil(num_threads).max(1);
+
+    std::thread::scope(|s| {
+        let handles = texts
+            .chunks(chunk_size)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|t| tokenize_str(aho, normalization, t))
+                        .collect_vec()
+                })
+            })
+            .collect_vec();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect()
+    })
+}
+
+/// The data files and the settings that decide which samples are read from them, shared by the readers.
+struct ReaderSource {
+    data_paths: Vec<PathBuf>,
+    pipeline: Pipeline,
+    normalization: Normalization,
+    split: Option<(Splitter, usize)>,
+}
+
+impl ReaderSource {
+    /// Parse the arguments shared by the reader constructors.
+    /// `pipeline` is a JSON list of preprocessing stages, by default only samples containing RTL text are dropped.
     fn new(
-        tokens: Vec<Vec<u8>>,
         data_paths: Vec<PathBuf>,
-        batch_size: usize,
-        seq_len: usize,
-        bucket_count: usize,
-        queue_size: usize,
+        normalization: Normalization,
+        pipeline: Option<&str>,
+        split: Option<&str>,
+        split_config: Option<&str>,
     ) -> PyResult<Self> {
+        let pipeline = match pipeline {
+            None => Pipeline::ltr_only(),
+            Some(pipeline) => {
+                let stages = serde_json::from_str(pipeline)
+                    .map_err(|e| PyValueError::new_err(format!("Invalid pipeline: {}", e)))?;
+                Pipeline::new(stages)?
+            }
+        };
+        let split_config: SplitConfig = match split_config {
+            None => SplitConfig::default(),
+            Some(config) => serde_json::from_str(config)
+                .map_err(|e| PyValueError::new_err(format!("Invalid split config: {}", e)))?,
+        };
+        let split = match split {
+            None => None,
+            Some(split) => {
+                let splitter = Splitter::new(&split_config)?;
+                let index = splitter.index(split)?;
+                Some((splitter, index))
+            }
+        };
+
         for path in &data_paths {
             if !path.exists() {
                 return Err(std::io::Error::new(
@@ -73,38 +457,527 @@ impl BatchTokenReader {
             }
         }
 
-        let batcher = Batcher::new(batch_size, seq_len, bucket_count, tokens);
+        Ok(ReaderSource {
+            data_paths,
+            pipeline,
+            normalization,
+            split,
+        })
+    }
+
+    /// Open a reader for `path`, the pipeline is passed from reader to reader so its counters keep accumulating.
+    fn open(&self, path: &Path, pipeline: Pipeline) -> std::io::Result<DecodeSampleReader<File>> {
+        let mut reader = SampleReader::open(path, pipeline, self.normalization)?;
+        if let Some((splitter, split)) = &self.split {
+            reader = reader.with_split(splitter.clone(), *split);
+        }
+        Ok(reader)
+    }
+}
+
+#[pymethods]
+impl BatchTokenReader {
+    /// `vocab` and `normalization` are interpreted the same way as for `Tokenizer`.
+    /// `pipeline` is a JSON list of preprocessing stages, by default only samples containing RTL text are dropped.
+    /// If `split` is given only the samples assigned to that split are used,
+    /// `split_config` is a JSON object with the split settings, by default "train" and "val" with 99% and 1%.
+    /// If `keep_short_tails` the tokens at the end of a sample that don't fill a row are padded instead of dropped.
+    ///
+    /// If `pad_multiple` is given rows are grouped by length and each batch is only as wide as its longest row,
+    /// rounded up to a multiple of `pad_multiple`. Batches then have `batch_size` rows,
+    /// or if `max_tokens` is given as many rows as fit in that many tokens,
+    /// and iterating yields `(tokens, lengths)` instead of only the tokens.
+    ///
+    /// `schedule` is a list of `(batch, seq_len, batch_size)` steps that change the shape from the given batch index on,
+    /// `seq_len` and `batch_size` are used before the first step. When resuming set `start_batch`
+    /// to the index of the next batch, available as `batch_index` in `stats()`, to continue at the right step.
+    ///
+    /// Waiting for a batch releases the GIL, if it takes longer than `timeout` seconds a `TimeoutError` is raised.
+    /// The reader can be closed with `close()` or by using it as a context manager,
+    /// and supports both normal and `async for` iteration.
+    ///
+    /// `dtype` is the token type, "int32" padded with -1, or "uint16" or "uint32" padded with their maximum value.
+    /// The token arrays share their memory with the reader without copying,
+    /// once an array and all views of it are garbage collected its memory is reused for a later batch.
+    #[new]
+    #[args(
+        normalization = "None",
+        pipeline = "None",
+        split = "None",
+        split_config = "None",
+        keep_short_tails = "false",
+        pad_multiple = "None",
+        max_tokens = "None",
+        schedule = "None",
+        start_batch = "0",
+        timeout = "None",
+        dtype = "\"int32\""
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        vocab: VocabSource,
+        data_paths: Vec<PathBuf>,
+        batch_size: usize,
+        seq_len: usize,
+        bucket_count: usize,
+        queue_size: usize,
+        normalization: Option<&str>,
+        pipeline: Option<&str>,
+        split: Option<&str>,
+        split_config: Option<&str>,
+        keep_short_tails: bool,
+        pad_multiple: Option<usize>,
+        max_tokens: Option<usize>,
+        schedule: Option<Vec<(usize, usize, usize)>>,
+        start_batch: usize,
+        timeout: Option<f64>,
+        dtype: &str,
+    ) -> PyResult<Self> {
+        let (tokens, normalization) = vocab.resolve(normalization)?;
+        let source = ReaderSource::new(data_paths, normalization, pipeline, split, split_config)?;
+
+        let shape = match (pad_multiple, max_tokens) {
+            (None, None) => BatchShape::Fixed,
+            (Some(pad_multiple), max_tokens) => {
+                if pad_multiple == 0 || max_tokens == Some(0) {
+                    return Err(PyValueError::new_err(
+                        "pad_multiple and max_tokens must be positive",
+                    ));
+                }
+                BatchShape::Dynamic {
+                    pad_multiple,
+                    max_tokens,
+                }
+            }
+            (None, Some(_)) => {
+                return Err(PyValueError::new_err(
+                    "max_tokens requires pad_multiple to be set",
+                ))
+            }
+        };
+
+        let vocab_size = tokens.len();
+        let mut batcher = Batcher::new(batch_size, seq_len, bucket_count, tokens)
+            .with_keep_short_tails(keep_short_tails)
+            .with_shape(shape);
+        if let Some(schedule) = schedule {
+            let steps = schedule
+                .into_iter()
+                .map(|(batch, seq_len, batch_size)| ScheduleStep {
+                    batch,
+                    seq_len,
+                    batch_size,
+                })
+                .collect();
+            let schedule =
+                Schedule::new(steps).map_err(|e| PyValueError::new_err(e.to_string()))?;
+            batcher = batcher.with_schedule(schedule, start_batch);
+        }
+        let first_batch_index = batcher.batch_index();
         let (sender, receiver) = flume::bounded(queue_size);
+        let (recycle, recycled) = flume::bounded(queue_size.max(1));
+        let stats = Arc::new(Mutex::new(ReaderStats::default()));
 
-        std::thread::Builder::new()
-            .name(String::from("BatchTokenReader"))
-            .spawn(move || batcher_thread_main(batcher, sender, data_paths))?;
+        let thread_stats = stats.clone();
+        let spawn = match dtype {
+            "int32" => spawn_batcher::<i32>,
+            "uint16" => spawn_batcher::<u16>,
+            "uint32" => spawn_batcher::<u32>,
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "Unknown dtype {:?}, expected int32, uint16 or uint32",
+                    dtype
+                )))
+            }
+        };
+        let thread = spawn(vocab_size, batcher, sender, recycled, source, thread_stats)?;
 
-        Ok(BatchTokenReader { receiver })
+        Ok(BatchTokenReader {
+            handle: ReaderHandle::new(receiver, thread, timeout)?,
+            shape,
+            queue_size,
+            recycle,
+            first_batch_index,
+            start: Instant::now(),
+            stats,
+        })
+    }
+
+    /// Counters and throughput of the reader, as a dict.
+    ///
+    /// The counters are updated by the reader thread for every batch, so they include batches still in the queue.
+    /// The exception is `batch_index`, the index of the next batch iteration returns, which is the one to resume from.
+    /// The `*_tokens_per_second` values are based on the time the thread spent in each phase,
+    /// where decoding includes reading, the preprocessing pipeline and normalization.
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let stats = self.stats.lock().unwrap().clone();
+        let batcher = &stats.batcher;
+        let dict = PyDict::new(py);
+
+        dict.set_item("samples", batcher.sample_count)?;
+        dict.set_item("tokens", batcher.token_count)?;
+        dict.set_item("batches", batcher.batch_count)?;
+        dict.set_item(
+            "batch_index",
+            self.first_batch_index + self.handle.received(),
+        )?;
+        dict.set_item("seq_len", stats.seq_len)?;
+        dict.set_item("batch_size", stats.batch_size)?;
+        dict.set_item("bytes_read", stats.bytes_read)?;
+        dict.set_item("used_tokens", batcher.used_count)?;
+        dict.set_item("padding_tokens", batcher.padding_count)?;
+        dict.set_item("dropped_start_tokens", batcher.dropped_start_count)?;
+        dict.set_item("dropped_tail_tokens", batcher.dropped_tail_count)?;
+        dict.set_item("padding_fraction", batcher.padding_fraction())?;
+        dict.set_item("utilisation", batcher.utilisation())?;
+
+        dict.set_item("queue_len", self.handle.queue_len())?;
+        dict.set_item("queue_size", self.queue_size)?;
+
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let tokens = batcher.token_count as f64;
+        let per_second = |time: Duration| tokens / time.as_secs_f64().max(1e-9);
+        dict.set_item("elapsed_seconds", elapsed)?;
+        dict.set_item("tokens_per_second", tokens / elapsed.max(1e-9))?;
+        dict.set_item("decode_tokens_per_second", per_second(stats.decode_time))?;
+        dict.set_item(
+            "tokenize_tokens_per_second",
+            per_second(stats.tokenize_time),
+        )?;
+        dict.set_item("batch_tokens_per_second", per_second(stats.batch_time))?;
+
+        let pipeline = stats
+            .pipeline
+            .iter()
+            .map(|(name, counts)| {
+                let stage = PyDict::new(py);
+                stage.set_item("stage", *name)?;
+                stage.set_item("samples", counts.samples)?;
+                stage.set_item("dropped", counts.dropped)?;
+                stage.set_item("changed", counts.changed)?;
+                stage.set_item("details", counts.details.clone().into_py(py))?;
+                Ok(stage)
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        dict.set_item("pipeline", pipeline)?;
+
+        Ok(dict)
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python) -> PyResult<Option<PyObject>> {
+        let batch = self.handle.recv(py)?;
+        batch.map(|batch| self.batch_to_py(py, batch)).transpose()
+    }
+
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__(slf: PyRef<'_, Self>, py: Python) -> PyResult<Option<PyObject>> {
+        next_in_executor(py, slf.into_py(py))
+    }
+
+    #[pyo3(name = "_next_async")]
+    fn next_async(&self, py: Python) -> PyResult<PyObject> {
+        match self.handle.recv(py)? {
+            Some(batch) => self.batch_to_py(py, batch),
+            None => Err(PyStopAsyncIteration::new_err(())),
+        }
+    }
+
+    /// Stop the reader thread and wait for it to finish, further iteration raises a `ValueError`.
+    /// Iteration that is already waiting for a batch in another thread stops.
+    fn close(&self, py: Python) -> PyResult<()> {
+        self.handle.close(py)
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        py: Python,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<bool> {
+        self.handle.close(py)?;
+        Ok(false)
+    }
+}
+
+#[pymethods]
+impl EvalTokenReader {
+    /// Read every token of the data exactly once, in order, for evaluation.
+    ///
+    /// Samples are split into windows of `seq_len` tokens starting `stride` tokens apart (by default `seq_len`),
+    /// the overlap is context that is not scored again.
+    /// The other arguments are interpreted the same way as for `BatchTokenReader`,
+    /// including the `timeout`, `close()` and context manager and async iteration support.
+    #[new]
+    #[args(
+        stride = "None",
+        queue_size = "16",
+        normalization = "None",
+        pipeline = "None",
+        split = "None",
+        split_config = "None",
+        timeout = "None"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        vocab: VocabSource,
+        data_paths: Vec<PathBuf>,
+        batch_size: usize,
+        seq_len: usize,
+        stride: Option<usize>,
+        queue_size: usize,
+        normalization: Option<&str>,
+        pipeline: Option<&str>,
+        split: Option<&str>,
+        split_config: Option<&str>,
+        timeout: Option<f64>,
+    ) -> PyResult<Self> {
+        let (tokens, normalization) = vocab.resolve(normalization)?;
+        let source = ReaderSource::new(data_paths, normalization, pipeline, split, split_config)?;
+
+        let stride = stride.unwrap_or(seq_len);
+        if batch_size == 0 || seq_len == 0 || stride == 0 || stride > seq_len {
+            return Err(PyValueError::new_err(
+                "batch_size and seq_len must be positive and stride must be in 1..=seq_len",
+            ));
+        }
+
+        let batcher = SequentialBatcher::new(batch_size, seq_len, stride, tokens);
+        let (sender, receiver) = flume::bounded(queue_size);
+
+        let thread = std::thread::Builder::new()
+            .name(String::from("EvalTokenReader"))
+            .spawn(move || {
+                thread_main(sender, |sender| {
+                    sequential_thread_main(batcher, sender, source)
+                })
+            })?;
+
+        Ok(EvalTokenReader {
+            handle: ReaderHandle::new(receiver, thread, timeout)?,
+        })
     }
 
     fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
         slf
     }
 
-    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<&'py PyArray2<i32>>> {
-        let batch = match self.receiver.recv() {
-            Ok(Message::Batch(batch)) => batch,
-            Ok(Message::Error(err)) => return Err(err.into()),
-            Err(RecvError::Disconnected) => return Ok(None),
+    /// Returns `(tokens, loss_mask, samples, start_indices)`, where `tokens` and `loss_mask` are padded
+    /// with -1 and false respectively, and for each row `samples` is the index of the sample it comes from
+    /// and `start_indices` the index of its first token within that sample.
+    fn __next__(&self, py: Python) -> PyResult<Option<PyObject>> {
+        let batch = self.handle.recv(py)?;
+        Ok(batch.map(|batch| sequential_batch_to_py(py, batch)))
+    }
+
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__(slf: PyRef<'_, Self>, py: Python) -> PyResult<Option<PyObject>> {
+        next_in_executor(py, slf.into_py(py))
+    }
+
+    #[pyo3(name = "_next_async")]
+    fn next_async(&self, py: Python) -> PyResult<PyObject> {
+        match self.handle.recv(py)? {
+            Some(batch) => Ok(sequential_batch_to_py(py, batch)),
+            None => Err(PyStopAsyncIteration::new_err(())),
+        }
+    }
+
+    /// Stop the reader thread and wait for it to finish, further iteration raises a `ValueError`.
+    /// Iteration that is already waiting for a batch in another thread stops.
+    fn close(&self, py: Python) -> PyResult<()> {
+        self.handle.close(py)
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        py: Python,
+        _exc_type: PyObject,
+        _exc_value: PyObject,
+        _traceback: PyObject,
+    ) -> PyResult<bool> {
+        self.handle.close(py)?;
+        Ok(false)
+    }
+}
+
+impl BatchTokenReader {
+    fn batch_to_py(&self, py: Python, batch: AnyBatch) -> PyResult<PyObject> {
+        let (tokens, lengths) = match batch {
+            AnyBatch::I32(batch) => self.tokens_to_py(py, batch)?,
+            AnyBatch::U16(batch) => self.tokens_to_py(py, batch)?,
+            AnyBatch::U32(batch) => self.tokens_to_py(py, batch)?,
+        };
+        Ok(match self.shape {
+            BatchShape::Fixed => tokens,
+            BatchShape::Dynamic { .. } => (tokens, lengths.into_pyarray(py)).into_py(py),
+        })
+    }
+
+    /// Pass the tokens to numpy without copying, the buffer is recycled once numpy is done with it.
+    fn tokens_to_py<T: PyToken>(
+        &self,
+        py: Python,
+        batch: Batch<T>,
+    ) -> PyResult<(PyObject, Vec<i64>)> {
+        let lengths = batch.lengths.iter().map(|&l| l as i64).collect_vec();
+
+        let owner = PyCell::new(
+            py,
+            BatchBuffer {
+                batch: Some(T::wrap(batch)),
+                recycle: self.recycle.clone(),
+            },
+        )?;
+        let buffer = owner.borrow();
+        let batch = buffer.batch.as_ref().and_then(T::unwrap_ref).unwrap();
+        // SAFETY: the array keeps `owner` alive, and the tokens are not modified or moved out of it before it is dropped
+        let tokens = unsafe { PyArray2::borrow_from_array(&batch.tokens, owner) };
+
+        Ok((tokens.into_py(py), lengths))
+    }
+}
+
+fn sequential_batch_to_py(py: Python, batch: SequentialBatch) -> PyObject {
+    let to_i64 = |values: Vec<usize>| values.into_iter().map(|v| v as i64).collect_vec();
+    (
+        PyArray2::from_owned_array(py, batch.tokens),
+        PyArray2::from_owned_array(py, batch.loss_mask),
+        to_i64(batch.samples).into_pyarray(py),
+        to_i64(batch.start_indices).into_pyarray(py),
+    )
+        .into_py(py)
+}
+
+/// Schedule `reader._next_async()` on the default executor of the running asyncio loop, returning the future.
+fn next_in_executor(py: Python, reader: PyObject) -> PyResult<Option<PyObject>> {
+    let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+    let next = reader.getattr(py, "_next_async")?;
+    let future = event_loop.call_method1("run_in_executor", (py.None(), next))?;
+    Ok(Some(future.into()))
+}
+
+impl<B: Send> ReaderHandle<B> {
+    fn new(
+        receiver: Receiver<Message<B>>,
+        thread: JoinHandle<()>,
+        timeout: Option<f64>,
+    ) -> PyResult<Self> {
+        let timeout = match timeout {
+            None => None,
+            Some(timeout) if timeout.is_finite() && timeout > 0.0 => {
+                Some(Duration::from_secs_f64(timeout))
+            }
+            Some(_) => return Err(PyValueError::new_err("timeout must be positive")),
         };
 
-        Ok(Some(PyArray2::from_owned_array(py, batch.tokens)))
+        Ok(ReaderHandle {
+            receiver: Mutex::new(Some(receiver)),
+            thread: Mutex::new(Some(thread)),
+            timeout,
+            received: AtomicUsize::new(0),
+        })
+    }
+
+    /// A handle to the receiver, `None` if the reader is closed.
+    fn receiver(&self) -> Option<Receiver<Message<B>>> {
+        self.receiver.lock().unwrap().clone()
+    }
+
+    fn queue_len(&self) -> usize {
+        self.receiver
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |r| r.len())
+    }
+
+    /// The number of batches returned by `recv` so far.
+    fn received(&self) -> usize {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Wait for the next batch without holding the GIL, `None` if the reader thread is done
+    /// or if the reader got closed while waiting.
+    fn recv(&self, py: Python) -> PyResult<Option<B>> {
+        if self.receiver().is_none() {
+            return Err(PyValueError::new_err("Reader is closed"));
+        }
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let wait = match deadline {
+                None => SIGNAL_CHECK_INTERVAL,
+                Some(deadline) => {
+                    SIGNAL_CHECK_INTERVAL.min(deadline.saturating_duration_since(Instant::now()))
+                }
+            };
+
+            // take a new handle every time, so a concurrent close disconnects the thread within one interval
+            let receiver = match self.receiver() {
+                Some(receiver) => receiver,
+                None => return Ok(None),
+            };
+            match py.allow_threads(|| receiver.recv_timeout(wait)) {
+                Ok(Message::Batch(batch)) => {
+                    self.received.fetch_add(1, Ordering::Relaxed);
+                    return Ok(Some(batch));
+                }
+                Ok(Message::Error(err)) => return Err(read_error_to_py(py, err)),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+                Err(RecvTimeoutError::Timeout) => {
+                    py.check_signals()?;
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(PyTimeoutError::new_err(format!(
+                            "No batch received within {:?}",
+                            self.timeout.unwrap()
+                        )));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Disconnect from the reader thread so it stops at its next send, and wait for it to finish.
+    fn close(&self, py: Python) -> PyResult<()> {
+        drop(self.receiver.lock().unwrap().take());
+        let thread = self.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            py.allow_threads(|| thread.join())
+                .map_err(|_| PyRuntimeError::new_err("Reader thread panicked"))?;
+        }
+        Ok(())
     }
 }
 
-enum Message {
-    Batch(Batch),
+enum Message<B> {
+    Batch(B),
     Error(std::io::Error),
 }
 
-fn batcher_thread_main(batcher: Batcher, sender: Sender<Message>, data_paths: Vec<PathBuf>) {
-    match batcher_thread_main_inner(batcher, &sender, data_paths) {
+fn thread_main<B>(
+    sender: Sender<Message<B>>,
+    inner: impl FnOnce(&Sender<Message<B>>) -> std::io::Result<()>,
+) {
+    match inner(&sender) {
         Ok(()) => {}
         Err(err) => {
             // ignore errors caused by the sender, we're already closing everything anyway
@@ -116,31 +989,114 @@ fn batcher_thread_main(batcher: Batcher, sender: Sender<Message>, data_paths: Ve
     drop(sender);
 }
 
-fn batcher_thread_main_inner(
+fn spawn_batcher<T: PyToken>(
+    vocab_size: usize,
+    batcher: Batcher,
+    sender: Sender<Message<AnyBatch>>,
+    recycled: Receiver<AnyBatch>,
+    source: ReaderSource,
+    stats: Arc<Mutex<ReaderStats>>,
+) -> PyResult<JoinHandle<()>> {
+    if vocab_size > T::MAX_TOKENS {
+        return Err(PyValueError::new_err(format!(
+            "The vocab is too large for dtype {}",
+            T::DTYPE
+        )));
+    }
+
+    let thread = std::thread::Builder::new()
+        .name(String::from("BatchTokenReader"))
+        .spawn(move || {
+            thread_main(sender, |sender| {
+                batcher_thread_main::<T>(batcher, sender, &recycled, source, &stats)
+            })
+        })?;
+    Ok(thread)
+}
+
+fn batcher_thread_main<T: PyToken>(
     mut batcher: Batcher,
-    sender: &Sender<Message>,
-    data_paths: Vec<PathBuf>,
+    sender: &Sender<Message<AnyBatch>>,
+    recycled: &Receiver<AnyBatch>,
+    source: ReaderSource,
+    shared_stats: &Mutex<ReaderStats>,
 ) -> std::io::Result<()> {
+    let mut pipeline = source.pipeline.clone();
+    let mut stats = ReaderStats::default();
+    // the allocation for the next batch
+    let mut buffer: Vec<T> = vec![];
+    // bytes read by the previous readers
+    let mut bytes_read = 0;
+
+    let publish = |stats: &mut ReaderStats, batcher: &Batcher, pipeline: &Pipeline| {
+        stats.batcher = batcher.stats();
+        stats.seq_len = batcher.seq_len();
+        stats.batch_size = batcher.batch_size();
+        stats.pipeline = pipeline
+            .stages()
+            .iter()
+            .map(|s| s.name())
+            .zip(pipeline.counts().iter().cloned())
+            .collect();
+        *shared_stats.lock().unwrap() = stats.clone();
+    };
+
     'outer: loop {
         let mut all_empty = true;
+        // samples seen in the previous pass are not duplicates
+        pipeline.clear_state();
 
-        for path in &data_paths {
-            let file = File::open(path)?;
-            for sample in SampleReader::new_decode(file, true, true)? {
-                let sample = sample?;
-
-                if batcher.push_sample(&sample.text) {
-                    all_empty = false;
+        for path in &source.data_paths {
+            let mut reader = source.open(path, pipeline)?;
+            loop {
+                // stop early if the reader got closed while no batch was ready yet
+                if sender.is_disconnected() {
+                    break 'outer;
                 }
 
-                while let Some(batch) = batcher.pop_batch() {
-                    match sender.send(Message::Batch(batch)) {
+                let start = Instant::now();
+                let sample = match reader.next() {
+                    Some(sample) => sample?,
+                    None => break,
+                };
+                let decoded = Instant::now();
+                let pushed = batcher.push_sample(&sample.text);
+                let tokenized = Instant::now();
+                stats.decode_time += decoded - start;
+                stats.tokenize_time += tokenized - decoded;
+                all_empty &= !pushed;
+
+                loop {
+                    let start = Instant::now();
+                    if buffer.capacity() == 0 {
+                        // reuse the buffer of a batch python is done with
+                        if let Some(batch) = recycled.try_recv().ok().and_then(T::unwrap) {
+                            buffer = batch.into_buffer();
+                        }
+                    }
+                    let batch = batcher.pop_batch_into(&mut buffer);
+                    stats.batch_time += start.elapsed();
+                    let batch = match batch {
+                        Some(batch) => batch,
+                        None => break,
+                    };
+
+                    stats.bytes_read = bytes_read + reader.bytes_read();
+                    publish(&mut stats, &batcher, reader.pipeline());
+
+                    match sender.send(Message::Batch(T::wrap(batch))) {
                         Ok(()) => {}
                         // receiver got closed, we can stop as well
                         Err(SendError(_)) => break 'outer,
                     }
                 }
             }
+
+            bytes_read += reader.bytes_read();
+            pipeline = reader.into_pipeline();
+
+            stats.bytes_read = bytes_read;
+            publish(&mut stats, &batcher, &pipeline);
         }
 
         // none of the files (if any) contain a sample, break infinite loop
@@ -151,3 +1107,35 @@ fn batcher_thread_main_inner(
 
     Ok(())
 }
+
+fn sequential_thread_main(
+    mut batcher: SequentialBatcher,
+    sender: &Sender<Message<SequentialBatch>>,
+    source: ReaderSource,
+) -> std::io::Result<()> {
+    let mut pipeline = source.pipeline.clone();
+
+    // a single pass over the data
+    for path in &source.data_paths {
+        let mut reader = source.open(path, pipeline)?;
+        for sample in &mut reader {
+            if sender.is_disconnected() {
+                return Ok(());
+            }
+            batcher.push_sample(&sample?.text);
+
+            while let Some(batch) = batcher.pop_batch() {
+                if sender.send(Message::Batch(batch)).is_err() {
+                    // receiver got closed, we can stop as well
+                    return Ok(());
+                }
+            }
+        }
+        pipeline = reader.into_pipeline();
+    }
+
+    if let Some(batch) = batcher.pop_last_batch() {
+        let _ = sender.send(Message::Batch(batch));
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+import asyncio
+import json
+import os
+import tempfile
+import threading
+import unittest
+
+import ktoken
+
+# every byte is a token
+VOCAB = [[i] for i in range(256)]
+
+
+def write_zst(path, data: bytes):
+    # a single zstd frame with one raw block, so the test doesn't need a zstd module
+    assert len(data) < 256
+    header = b"\x28\xb5\x2f\xfd" + bytes([0x20, len(data)])
+    block = (1 | len(data) << 3).to_bytes(3, "little")
+    with open(path, "wb") as f:
+        f.write(header + block + data)
+
+
+class CloseTest(unittest.TestCase):
+    def setUp(self):
+        self.dir = tempfile.TemporaryDirectory()
+        self.path = os.path.join(self.dir.name, "data.jsonl.zst")
+        sample = {"text": "some text", "meta": {"pile_set_name": "Pile-CC"}}
+        write_zst(self.path, (json.dumps(sample) + "\n").encode())
+
+    def tearDown(self):
+        self.dir.cleanup()
+
+    def reader(self):
+        # a batch is only built once all buckets are filled, which never happens
+        return ktoken.BatchTokenReader(
+            VOCAB, [self.path],
+            batch_size=1, seq_len=8, bucket_count=10 ** 9, queue_size=1,
+            normalization="none",
+        )
+
+    def test_close_while_anext_pending(self):
+        async def main():
+            reader = self.reader()
+            pending = asyncio.ensure_future(reader.__anext__())
+            await asyncio.sleep(0.2)
+            self.assertFalse(pending.done())
+
+            reader.close()
+            with self.assertRaises(StopAsyncIteration):
+                await asyncio.wait_for(pending, timeout=5)
+            with self.assertRaises(ValueError):
+                next(reader)
+
+        asyncio.run(main())
+
+    def test_close_while_next_blocked(self):
+        reader = self.reader()
+        result = []
+
+        def consume():
+            result.append(next(reader, "stopped"))
+
+        thread = threading.Thread(target=consume)
+        thread.start()
+        thread.join(timeout=0.2)
+        self.assertTrue(thread.is_alive())
+
+        reader.close()
+        thread.join(timeout=5)
+        self.assertFalse(thread.is_alive())
+        self.assertEqual(result, ["stopped"])
+
+
+if __name__ == "__main__":
+    unittest.main()